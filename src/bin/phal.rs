@@ -9,24 +9,297 @@
 extern crate phal;
 
 use phal::{
-    limb::{Limb, LimbTypes},
-    pin, serial, xmodem,
-    server::PHALServer,
+    limb::{LimbBindings, LimbTypes},
+    pin, pwm, serial,
+    server::{PHALServer, DEFAULT_WORKERS},
+    soft_pwm, xmodem,
 };
 
-use std::collections::HashMap;
+use std::{collections::HashMap, env, fs, process, str::FromStr};
+
+const USAGE: &str = "\
+Usage: phal [OPTIONS]
+
+Options:
+  -a, --address <ADDRESS>    Address to listen on [default: 0.0.0.0]
+  -p, --port <PORT>          Port to listen on [default: 8000]
+  -c, --config <FILE>        Configuration to apply before serving
+  -s, --state-file <FILE>    File to save the configuration to after each
+                             change. If it exists on start-up, it is applied
+                             instead of --config, leaving out any limbs which
+                             can't be restored.
+  -w, --workers <COUNT>      Number of requests handled at once [default: 8]
+  -l, --log-level <LEVEL>    One of off, error, warn, info, debug or trace
+                             [default: info]
+      --check-config <FILE>  Check that a configuration can be applied, then
+                             exit. This opens every limb in the file.
+      --list-types           List the available limb types, then exit
+  -h, --help                 Print this message, then exit
+  -V, --version              Print the version, then exit
+";
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Serve,
+    CheckConfig(String),
+    ListTypes,
+    Help,
+    Version,
+}
+
+struct Options {
+    action: Action,
+    address: String,
+    port: u16,
+    config: Option<String>,
+    state_file: Option<String>,
+    workers: usize,
+    log_level: log::LevelFilter,
+}
+
+impl Options {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            action: Action::Serve,
+            address: "0.0.0.0".to_owned(),
+            port: 8000,
+            config: None,
+            state_file: None,
+            workers: DEFAULT_WORKERS,
+            log_level: log::LevelFilter::Info,
+        };
+        while let Some(arg) = args.next() {
+            // Accept both "--option value" and "--option=value".
+            let (flag, inline_value) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => {
+                    (arg[..i].to_owned(), Some(arg[i + 1..].to_owned()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} requires a value", flag))
+            };
+            match flag.as_ref() {
+                "-a" | "--address" => options.address = value()?,
+                "-p" | "--port" => options.port = parse(&flag, &value()?)?,
+                "-c" | "--config" => options.config = Some(value()?),
+                "-s" | "--state-file" => options.state_file = Some(value()?),
+                "-w" | "--workers" => options.workers = parse(&flag, &value()?)?,
+                "-l" | "--log-level" => options.log_level = parse(&flag, &value()?)?,
+                "--check-config" => options.action = Action::CheckConfig(value()?),
+                "--list-types" => options.action = Action::ListTypes,
+                "-h" | "--help" => options.action = Action::Help,
+                "-V" | "--version" => options.action = Action::Version,
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value \"{}\" for {}", value, flag))
+}
+
+/// Writes log records to standard error.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+fn read_config(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|error| {
+        eprintln!("Failed to read {}: {}", path, error);
+        process::exit(1);
+    })
+}
+
+fn check_config(path: &str, types: &LimbTypes) {
+    match LimbBindings::from_json(&read_config(path), types) {
+        Ok(_) => println!("{}: OK", path),
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}: {}", path, error);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn serve(options: &Options, types: LimbTypes) {
+    let address = (options.address.as_str(), options.port);
+    let mut server = match PHALServer::new(types, address) {
+        Ok(server) => server.with_workers(options.workers),
+        Err(error) => {
+            eprintln!("Failed to start server: {}", error);
+            process::exit(1);
+        }
+    };
+    let mut restored = false;
+    if let Some(path) = &options.state_file {
+        server = server.with_state_file(path);
+        match server.restore_state() {
+            Ok(found) => restored = found,
+            Err(errors) => {
+                for error in &errors {
+                    log::error!("Failed to restore {}: {}", path, error);
+                }
+                // Start with the limbs which could be restored, unless the
+                // file couldn't be used at all.
+                restored = errors.iter().all(|e| e.limb().is_some());
+                if !restored {
+                    log::warn!("Falling back to the configuration given with --config.");
+                }
+            }
+        }
+    }
+    if let (Some(path), false) = (&options.config, restored) {
+        if let Err(errors) = server.configure(&read_config(path)) {
+            for error in errors {
+                eprintln!("{}: {}", path, error);
+            }
+            process::exit(1);
+        }
+    }
+    server.run();
+    eprintln!("The server stopped unexpectedly.");
+    process::exit(1);
+}
 
 fn main() {
-    let address = "0.0.0.0:8000";
+    let options = Options::from_args(env::args().skip(1)).unwrap_or_else(|error| {
+        eprintln!("phal: {}\n\n{}", error, USAGE);
+        process::exit(2);
+    });
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(options.log_level);
+
     let types = limb_types![
         ("output-pin", pin::OutputPin),
         ("input-pin", pin::InputPin),
+        ("gpio-bus", pin::GpioBus),
+        ("soft-pwm", soft_pwm::SoftPwm),
+        ("pwm", pwm::Pwm),
         ("serial", serial::Serial),
         ("xmodem", xmodem::XModem)
     ];
 
-    match PHALServer::run_new(types, address) {
-        Ok(_) => eprintln!("The server stopped unexpectedly."),
-        Err(error) => eprintln!("Failed to start server: {}", error),
+    match &options.action {
+        Action::Serve => serve(&options, types),
+        Action::CheckConfig(path) => check_config(path, &types),
+        Action::ListTypes => {
+            let mut names: Vec<&String> = types.names().collect();
+            names.sort();
+            for name in names {
+                println!("{}", name);
+            }
+        }
+        Action::Help => print!("{}", USAGE),
+        Action::Version => println!("phal {}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Result<Options, String> {
+        Options::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults_are_used_without_arguments() {
+        let options = options(&[]).unwrap();
+        assert_eq!(options.action, Action::Serve);
+        assert_eq!(options.address, "0.0.0.0");
+        assert_eq!(options.port, 8000);
+        assert_eq!(options.config, None);
+        assert_eq!(options.state_file, None);
+        assert_eq!(options.workers, DEFAULT_WORKERS);
+        assert_eq!(options.log_level, log::LevelFilter::Info);
+    }
+
+    #[test]
+    fn short_options_take_the_next_argument() {
+        let args = [
+            "-a",
+            "127.0.0.1",
+            "-p",
+            "9000",
+            "-c",
+            "rig.json",
+            "-s",
+            "state.json",
+            "-w",
+            "2",
+            "-l",
+            "debug",
+        ];
+        let options = options(&args).unwrap();
+        assert_eq!(options.address, "127.0.0.1");
+        assert_eq!(options.port, 9000);
+        assert_eq!(options.config.as_deref(), Some("rig.json"));
+        assert_eq!(options.state_file.as_deref(), Some("state.json"));
+        assert_eq!(options.workers, 2);
+        assert_eq!(options.log_level, log::LevelFilter::Debug);
+    }
+
+    #[test]
+    fn long_options_take_the_next_argument_or_an_inline_value() {
+        let args = ["--address=::1", "--port", "9000", "--log-level=off"];
+        let options = options(&args).unwrap();
+        assert_eq!(options.address, "::1");
+        assert_eq!(options.port, 9000);
+        assert_eq!(options.log_level, log::LevelFilter::Off);
+    }
+
+    #[test]
+    fn actions_other_than_serving_are_selected() {
+        let action = |args: &[&str]| options(args).unwrap().action;
+        assert_eq!(
+            action(&["--check-config", "rig.json"]),
+            Action::CheckConfig("rig.json".to_owned())
+        );
+        assert_eq!(
+            action(&["--check-config=rig.json"]),
+            Action::CheckConfig("rig.json".to_owned())
+        );
+        assert_eq!(action(&["--list-types"]), Action::ListTypes);
+        assert_eq!(action(&["--help"]), Action::Help);
+        assert_eq!(action(&["-h"]), Action::Help);
+        assert_eq!(action(&["-V"]), Action::Version);
+    }
+
+    #[test]
+    fn invalid_arguments_are_reported() {
+        let error = |args: &[&str]| options(args).err().unwrap();
+        assert_eq!(error(&["-p"]), "-p requires a value");
+        assert_eq!(
+            error(&["--check-config"]),
+            "--check-config requires a value"
+        );
+        assert_eq!(error(&["-p", "http"]), "invalid value \"http\" for -p");
+        assert_eq!(error(&["-p", "70000"]), "invalid value \"70000\" for -p");
+        assert_eq!(error(&["-l", "loud"]), "invalid value \"loud\" for -l");
+        assert_eq!(error(&["--verbose"]), "unknown option --verbose");
+        assert_eq!(error(&["-x=1"]), "unknown option -x=1");
     }
 }
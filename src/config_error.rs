@@ -0,0 +1,225 @@
+// Copyright (C) 2020 Arron Speake
+
+use crate::system_serial;
+use gpio_cdev as cdev;
+use nix::errno::Errno;
+use serde_json as json;
+use std::{fmt, io};
+
+/// A problem with one member of a configuration, located by the name of the
+/// limb it belongs to and the JSON path of the offending field.
+#[derive(Debug)]
+pub struct ConfigError {
+    limb: Option<String>,
+    field: Option<String>,
+    expected: String,
+    source: Option<io::Error>,
+}
+
+impl ConfigError {
+    /// An error in the field `field` of a limb's configuration, which should
+    /// have been `expected`.
+    pub fn new(field: &str, expected: &str) -> Self {
+        Self {
+            limb: None,
+            field: Some(field.to_owned()),
+            expected: expected.to_owned(),
+            source: None,
+        }
+    }
+
+    /// An error in a configuration as a whole, or in a limb's configuration
+    /// as a whole once given a limb name.
+    pub fn root(expected: &str) -> Self {
+        Self {
+            limb: None,
+            field: None,
+            expected: expected.to_owned(),
+            source: None,
+        }
+    }
+
+    /// Records the OS error which caused a field to be rejected.
+    pub fn with_source(mut self, source: io::Error) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Records the OS error from gpio-cdev which caused a field to be
+    /// rejected.
+    pub fn with_cdev_source(self, source: cdev::errors::Error) -> Self {
+        self.with_source(io_error_from_cdev(source))
+    }
+
+    /// Records the OS error from the serial crate which caused a field to be
+    /// rejected.
+    pub fn with_serial_source(self, source: system_serial::Error) -> Self {
+        self.with_source(io_error_from_serial(source))
+    }
+
+    /// Records the name of the limb whose configuration was rejected.
+    pub fn in_limb(mut self, limb: &str) -> Self {
+        self.limb = Some(limb.to_owned());
+        self
+    }
+
+    pub fn limb(&self) -> Option<&str> {
+        self.limb.as_deref()
+    }
+
+    /// The JSON path of the rejected field, e.g. `gpio_10.pin-type`.
+    pub fn path(&self) -> String {
+        let parts: Vec<&str> = self
+            .limb
+            .iter()
+            .chain(self.field.iter())
+            .map(String::as_str)
+            .collect();
+        parts.join(".")
+    }
+
+    pub fn expected(&self) -> &str {
+        &self.expected
+    }
+
+    pub fn os_error(&self) -> Option<&io::Error> {
+        self.source.as_ref()
+    }
+
+    pub fn to_json(&self) -> json::Value {
+        let os_error = match &self.source {
+            Some(error) => match error.raw_os_error() {
+                Some(errno) => json::json!({
+                    "errno": errno,
+                    "name": format!("{:?}", Errno::from_i32(errno)),
+                    "message": Errno::from_i32(errno).desc(),
+                }),
+                None => json::json!({ "message": error.to_string() }),
+            },
+            None => json::Value::Null,
+        };
+        json::json!({
+            "limb": self.limb,
+            "path": self.path(),
+            "expected": self.expected,
+            "os-error": os_error,
+        })
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = self.path();
+        if path.is_empty() {
+            write!(f, "expected {}", self.expected)?;
+        } else {
+            write!(f, "{}: expected {}", path, self.expected)?;
+        }
+        if let Some(source) = &self.source {
+            write!(f, " ({})", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// Converts a gpio-cdev error to an `io::Error`, keeping the errno of the
+/// first OS error in its chain of causes where there is one.
+pub(crate) fn io_error_from_cdev(error: cdev::errors::Error) -> io::Error {
+    let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(&error);
+    let mut errno = None;
+    while let (Some(e), None) = (cause, errno) {
+        errno = errno_of(e);
+        cause = e.source();
+    }
+    match errno {
+        Some(errno) => io::Error::from_raw_os_error(errno),
+        None => {
+            let causes: Vec<String> = error.iter().map(|e| e.to_string()).collect();
+            io::Error::other(causes.join(": "))
+        }
+    }
+}
+
+/// The highest errno which is looked for by `io_error_from_serial`.
+const MAX_ERRNO: i32 = 255;
+
+/// Converts a serial port error to an `io::Error`. The serial crate only
+/// keeps the description of an OS error, so its errno is found again by
+/// comparing that with the description of each errno.
+pub(crate) fn io_error_from_serial(error: system_serial::Error) -> io::Error {
+    let description = error.to_string();
+    let errno = (1..=MAX_ERRNO).find(|&errno| {
+        let os_error = io::Error::from_raw_os_error(errno).to_string();
+        let suffix = format!(" (os error {})", errno);
+        os_error.strip_suffix(&suffix) == Some(description.as_str())
+    });
+    match errno {
+        Some(errno) => io::Error::from_raw_os_error(errno),
+        None => error.into(),
+    }
+}
+
+/// The errno of an error from the OS, as gpio-cdev passes them on.
+fn errno_of(error: &(dyn std::error::Error + 'static)) -> Option<i32> {
+    if let Some(nix::Error::Sys(errno)) = error.downcast_ref::<nix::Error>() {
+        return Some(*errno as i32);
+    }
+    if let Some(error) = error.downcast_ref::<io::Error>() {
+        return error.raw_os_error();
+    }
+    match error.downcast_ref::<cdev::errors::Error>()?.kind() {
+        cdev::errors::ErrorKind::Nix(nix::Error::Sys(errno)) => Some(*errno as i32),
+        cdev::errors::ErrorKind::Io(e) => e.raw_os_error(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdev::errors::ResultExt;
+
+    #[test]
+    fn the_errno_beneath_a_gpio_cdev_error_is_kept() {
+        let busy: Result<(), nix::Error> = Err(nix::Error::Sys(Errno::EBUSY));
+        let error = busy
+            .chain_err(|| "linehandle request ioctl failed")
+            .unwrap_err();
+        assert_eq!(io_error_from_cdev(error).raw_os_error(), Some(16));
+        let busy: cdev::errors::Result<()> = Err(io::Error::from_raw_os_error(16).into());
+        let error = busy
+            .chain_err(|| "linehandle request ioctl failed")
+            .unwrap_err();
+        assert_eq!(io_error_from_cdev(error).raw_os_error(), Some(16));
+        let error = cdev::errors::Error::from(nix::Error::Sys(Errno::ENOENT));
+        assert_eq!(io_error_from_cdev(error).raw_os_error(), Some(2));
+    }
+
+    #[test]
+    fn the_errno_of_a_serial_error_is_found_from_its_description() {
+        let missing = system_serial::open("/dev/ttyNOPE").err().unwrap();
+        let error = io_error_from_serial(missing);
+        assert_eq!(error.raw_os_error(), Some(Errno::ENOENT as i32));
+        let other = system_serial::Error::new(system_serial::ErrorKind::InvalidInput, "bad");
+        let error = io_error_from_serial(other);
+        assert_eq!(error.raw_os_error(), None);
+        assert_eq!(error.to_string(), "bad");
+    }
+
+    #[test]
+    fn gpio_cdev_errors_without_an_errno_keep_their_messages() {
+        let failed: cdev::errors::Result<()> = Err("no such line".into());
+        let error = failed.chain_err(|| "getting line failed").unwrap_err();
+        let error = io_error_from_cdev(error);
+        assert_eq!(error.raw_os_error(), None);
+        assert_eq!(error.to_string(), "getting line failed: no such line");
+    }
+}
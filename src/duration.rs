@@ -0,0 +1,62 @@
+// Copyright (C) 2020 Arron Speake
+
+use std::time::Duration;
+
+/// Parses a duration such as `200ms`, `1.5s` or `50us`. A number without a
+/// unit is taken to be in milliseconds. Durations too long to represent
+/// aren't accepted.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let seconds_per_unit = match unit.trim() {
+        "ns" => 1e-9,
+        "us" | "µs" => 1e-6,
+        "" | "ms" => 1e-3,
+        "s" => 1.0,
+        "m" | "min" => 60.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(number * seconds_per_unit).ok()
+}
+
+/// Formats a duration in the largest unit accepted by `parse_duration`
+/// which represents it exactly.
+pub fn format_duration(duration: Duration) -> String {
+    let nanos = duration.as_nanos();
+    if nanos == 0 {
+        "0ms".to_owned()
+    } else if nanos.is_multiple_of(1_000_000_000) {
+        format!("{}s", nanos / 1_000_000_000)
+    } else if nanos.is_multiple_of(1_000_000) {
+        format!("{}ms", nanos / 1_000_000)
+    } else if nanos.is_multiple_of(1_000) {
+        format!("{}us", nanos / 1_000)
+    } else {
+        format!("{}ns", nanos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units_are_parsed() {
+        assert_eq!(parse_duration("200ms"), Some(Duration::from_millis(200)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("50us"), Some(Duration::from_micros(50)));
+        assert_eq!(parse_duration("20"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_duration("2min"), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn unrepresentable_durations_are_rejected() {
+        assert_eq!(parse_duration("99999999999999999999999s"), None);
+        assert_eq!(parse_duration("1e400"), None);
+        assert_eq!(parse_duration("5h"), None);
+    }
+}
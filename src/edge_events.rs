@@ -0,0 +1,815 @@
+// Copyright (C) 2020 Arron Speake
+
+use gpio_cdev as cdev;
+use nix::poll::{poll, EventFlags, PollFd};
+use serde_json as json;
+use std::{
+    collections::VecDeque,
+    os::unix::io::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// The number of edges kept between reads. Older edges are dropped first.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How often the monitoring thread checks whether it should stop.
+const POLL_INTERVAL_MS: i32 = 100;
+
+/// Which edges of a line's signal are reported.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Edges {
+    Rising,
+    Falling,
+    Both,
+}
+
+impl Edges {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rising" => Some(Edges::Rising),
+            "falling" => Some(Edges::Falling),
+            "both" => Some(Edges::Both),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Edges::Rising => "rising",
+            Edges::Falling => "falling",
+            Edges::Both => "both",
+        }
+    }
+
+    pub fn includes(self, edge: Edge) -> bool {
+        match self {
+            Edges::Rising => edge.rising,
+            Edges::Falling => !edge.rising,
+            Edges::Both => true,
+        }
+    }
+
+    pub fn flags(self) -> cdev::EventRequestFlags {
+        match self {
+            Edges::Rising => cdev::EventRequestFlags::RISING_EDGE,
+            Edges::Falling => cdev::EventRequestFlags::FALLING_EDGE,
+            Edges::Both => cdev::EventRequestFlags::BOTH_EDGES,
+        }
+    }
+}
+
+/// A change in the level of a line, timestamped in nanoseconds by the
+/// kernel when the change happened.
+#[derive(Clone, Copy, Debug)]
+pub struct Edge {
+    pub timestamp: u64,
+    pub rising: bool,
+}
+
+impl Edge {
+    pub fn to_json(self) -> json::Value {
+        let edge = if self.rising { "rising" } else { "falling" };
+        json::json!({ "timestamp": self.timestamp, "edge": edge })
+    }
+}
+
+/// The edges received from a line, kept until they are read.
+struct Queue {
+    edges: VecDeque<Edge>,
+    capacity: usize,
+    /// The number of edges received since the monitor started.
+    received: u64,
+    /// The number of edges dropped since the last `take`.
+    dropped: u64,
+    /// The level of the line once debounced.
+    level: u8,
+    /// Why the monitoring thread stopped early, if it did.
+    failure: Option<String>,
+    /// The measurement being made, if there is one, which every edge is
+    /// added to whether or not it fits in the queue.
+    measurement: Option<Measurement>,
+}
+
+impl Queue {
+    fn new(capacity: usize, level: u8) -> Self {
+        Queue {
+            edges: VecDeque::new(),
+            capacity,
+            received: 0,
+            dropped: 0,
+            level,
+            failure: None,
+            measurement: None,
+        }
+    }
+
+    /// Adds an edge, if it is of a kind which is `reported`, dropping the
+    /// oldest edge if the queue is full.
+    fn push(&mut self, edge: Edge, reported: Option<Edges>) {
+        if !reported.is_some_and(|r| r.includes(edge)) {
+            return;
+        }
+        if let Some(measurement) = &mut self.measurement {
+            measurement.add(edge);
+        }
+        if self.edges.len() == self.capacity {
+            self.edges.pop_front();
+            if self.dropped == 0 {
+                log::warn!("Dropping edge events: the queue is full.");
+            }
+            self.dropped += 1;
+        }
+        self.edges.push_back(edge);
+        self.received += 1;
+    }
+
+    /// Removes the queued edges, along with the number of edges dropped
+    /// before them.
+    fn take(&mut self) -> (Vec<Edge>, u64) {
+        let dropped = std::mem::take(&mut self.dropped);
+        (self.edges.drain(..).collect(), dropped)
+    }
+
+    /// The edges received since `received` was `start` which are still
+    /// queued.
+    fn since(&self, start: u64) -> impl Iterator<Item = &Edge> {
+        // The queue ends with the newest edge, so the first edge after
+        // `start` is this far from the back, unless it has been dropped.
+        let new = (self.received - start).min(self.edges.len() as u64) as usize;
+        self.edges.range(self.edges.len() - new..)
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits for the queue to change or for `deadline` to pass. Without a
+    /// deadline, e.g. one too far off to represent, this waits for as long
+    /// as it takes.
+    fn wait<'a>(
+        &self,
+        queue: MutexGuard<'a, Queue>,
+        deadline: Option<Instant>,
+    ) -> MutexGuard<'a, Queue> {
+        match deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                let queue = self.changed.wait_timeout(queue, timeout);
+                queue.unwrap_or_else(PoisonError::into_inner).0
+            }
+            None => self
+                .changed
+                .wait(queue)
+                .unwrap_or_else(PoisonError::into_inner),
+        }
+    }
+}
+
+/// Reads the edges of a line on a background thread as they happen, so that
+/// pulses shorter than the time between two requests aren't missed.
+pub struct EdgeMonitor {
+    handle: Arc<cdev::LineEventHandle>,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    reported: Option<Edges>,
+    debounced: bool,
+}
+
+impl EdgeMonitor {
+    /// Watches a line requested for both edges if `debounce` is given, or
+    /// for the `reported` edges otherwise. Only `reported` edges are queued.
+    pub fn new(
+        handle: cdev::LineEventHandle,
+        reported: Option<Edges>,
+        debounce: Option<Duration>,
+    ) -> cdev::errors::Result<Self> {
+        let level = handle.get_value()?;
+        let handle = Arc::new(handle);
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::new(QUEUE_CAPACITY, level)),
+            changed: Condvar::new(),
+            stop: AtomicBool::new(false),
+        });
+        let thread = {
+            let handle = Arc::clone(&handle);
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || monitor(&handle, &shared, reported, debounce))
+        };
+        Ok(EdgeMonitor {
+            handle,
+            shared,
+            thread: Some(thread),
+            reported,
+            debounced: debounce.is_some(),
+        })
+    }
+
+    /// The current level of the line, or the level it last settled at if
+    /// it is debounced.
+    pub fn value(&self) -> cdev::errors::Result<u8> {
+        if self.debounced {
+            Ok(self.shared.lock().level)
+        } else {
+            self.handle.get_value()
+        }
+    }
+
+    /// Removes and returns the edges received since the last call, along
+    /// with the number of edges dropped before them because the queue was
+    /// full.
+    pub fn take(&self) -> Result<(Vec<Edge>, u64), String> {
+        let mut queue = self.shared.lock();
+        let edges = queue.take();
+        match &queue.failure {
+            Some(failure) => Err(failure.clone()),
+            None => Ok(edges),
+        }
+    }
+
+    /// Waits for the next edge, returning `None` if there is none within
+    /// `timeout`. Queued edges are left for `take`.
+    pub fn next(&self, timeout: Duration) -> Result<Option<Edge>, String> {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut queue = self.shared.lock();
+        let start = queue.received;
+        loop {
+            if let Some(failure) = &queue.failure {
+                return Err(failure.clone());
+            }
+            if queue.received > start {
+                return Ok(queue.since(start).next().copied());
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(None);
+            }
+            queue = self.shared.wait(queue, deadline);
+        }
+    }
+
+    /// Waits for `window` to pass, measuring the edges which arrive in the
+    /// meantime. Queued edges are left for `take`.
+    pub fn measure(&self, window: Duration) -> Result<Measurement, String> {
+        let deadline = Instant::now().checked_add(window);
+        let mut queue = self.shared.lock();
+        queue.measurement = Some(Measurement::new(self.reported == Some(Edges::Both)));
+        let result = loop {
+            if let Some(failure) = &queue.failure {
+                break Err(failure.clone());
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break Ok(());
+            }
+            queue = self.shared.wait(queue, deadline);
+        };
+        let measurement = queue.measurement.take();
+        result.map(|_| measurement.expect("only one measurement is made at a time"))
+    }
+}
+
+/// How many edges of one kind there have been, and when the first and last
+/// of them were.
+#[derive(Clone, Copy, Default)]
+struct EdgeTimes {
+    count: u64,
+    first: u64,
+    last: u64,
+}
+
+impl EdgeTimes {
+    fn add(&mut self, timestamp: u64) {
+        if self.count == 0 {
+            self.first = timestamp;
+        }
+        self.last = timestamp;
+        self.count += 1;
+    }
+}
+
+/// The number, shortest, longest and total of a set of pulse widths, in
+/// nanoseconds.
+#[derive(Clone, Copy, Default)]
+struct Widths {
+    count: u64,
+    min: u64,
+    max: u64,
+    total: u64,
+}
+
+impl Widths {
+    fn add(&mut self, width: u64) {
+        if self.count == 0 || width < self.min {
+            self.min = width;
+        }
+        self.max = self.max.max(width);
+        self.total += width;
+        self.count += 1;
+    }
+
+    fn range_to_json(self) -> json::Value {
+        if self.count == 0 {
+            json::Value::Null
+        } else {
+            json::json!({ "min": self.min, "max": self.max })
+        }
+    }
+}
+
+/// A summary of the signal which produced a series of edges, kept up to
+/// date as each edge arrives so that it covers every edge however many
+/// there are.
+pub struct Measurement {
+    rising: EdgeTimes,
+    falling: EdgeTimes,
+    high: Widths,
+    low: Widths,
+    last: Option<Edge>,
+    /// Whether both kinds of edge are measured, so that two edges of the
+    /// same kind in a row show that the edge between them was missed.
+    both: bool,
+    missed: u64,
+}
+
+impl Measurement {
+    pub fn new(both: bool) -> Self {
+        Measurement {
+            rising: EdgeTimes::default(),
+            falling: EdgeTimes::default(),
+            high: Widths::default(),
+            low: Widths::default(),
+            last: None,
+            both,
+            missed: 0,
+        }
+    }
+
+    fn add(&mut self, edge: Edge) {
+        if edge.rising {
+            self.rising.add(edge.timestamp);
+        } else {
+            self.falling.add(edge.timestamp);
+        }
+        match self.last {
+            Some(last) if last.rising != edge.rising => {
+                let width = edge.timestamp.saturating_sub(last.timestamp);
+                if last.rising {
+                    self.high.add(width);
+                } else {
+                    self.low.add(width);
+                }
+            }
+            Some(_) if self.both => self.missed += 1,
+            _ => (),
+        }
+        self.last = Some(edge);
+    }
+
+    /// The number of edges, the frequency in Hz, the duty cycle and the
+    /// shortest and longest high and low pulses in nanoseconds. Values which
+    /// the edges aren't enough to find are null, as is the number of edges
+    /// missed unless both kinds of edge are measured.
+    pub fn to_json(&self) -> json::Value {
+        // The period is measured between edges of the same kind, so that the
+        // frequency doesn't depend on the duty cycle.
+        let periodic = if self.rising.count >= self.falling.count {
+            self.rising
+        } else {
+            self.falling
+        };
+        let frequency = if periodic.count >= 2 && periodic.last > periodic.first {
+            let periods = (periodic.count - 1) as f64;
+            Some(periods * 1e9 / (periodic.last - periodic.first) as f64)
+        } else {
+            None
+        };
+        let duty_cycle = if self.high.count == 0 || self.low.count == 0 {
+            None
+        } else {
+            let total = self.high.total + self.low.total;
+            Some(self.high.total as f64 / total as f64)
+        };
+        json::json!({
+            "edges": self.rising.count + self.falling.count,
+            "rising": self.rising.count,
+            "falling": self.falling.count,
+            "frequency": frequency,
+            "duty-cycle": duty_cycle,
+            "high-time": self.high.range_to_json(),
+            "low-time": self.low.range_to_json(),
+            "missed": if self.both { Some(self.missed) } else { None },
+        })
+    }
+}
+
+impl Drop for EdgeMonitor {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Decides which edges of a bouncing line to believe: a change of level is
+/// only accepted once the line has stayed at the new level for the debounce
+/// time, and is reported with the timestamp of its last transition.
+struct Debouncer {
+    debounce: Duration,
+    /// The level the line last settled at.
+    level: u8,
+    /// An edge waiting for the line to settle, and when it will have. The
+    /// line never settles if that is too far off to represent.
+    pending: Option<(Edge, Option<Instant>)>,
+}
+
+impl Debouncer {
+    fn new(debounce: Duration, level: u8) -> Self {
+        Debouncer {
+            debounce,
+            level,
+            pending: None,
+        }
+    }
+
+    /// Records an edge which was read at `now`.
+    fn edge(&mut self, edge: Edge, now: Instant) {
+        self.pending = if edge.rising != (self.level == 1) {
+            Some((edge, now.checked_add(self.debounce)))
+        } else {
+            // The line bounced back to its settled level.
+            None
+        };
+    }
+
+    /// The edge accepted by `now`, if the line has settled at a new level.
+    fn settle(&mut self, now: Instant) -> Option<Edge> {
+        match self.pending {
+            Some((edge, Some(settled))) if now >= settled => {
+                self.level = edge.rising as u8;
+                self.pending = None;
+                Some(edge)
+            }
+            _ => None,
+        }
+    }
+
+    /// How long after `now` the pending edge will be accepted, if there is
+    /// one which will be.
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        match self.pending {
+            Some((_, Some(settled))) => Some(settled.saturating_duration_since(now)),
+            _ => None,
+        }
+    }
+}
+
+/// How long to poll the line for, in milliseconds: until a pending edge
+/// settles, rounded up, but no longer than `POLL_INTERVAL_MS` so that the
+/// thread still notices when it should stop.
+fn poll_timeout(remaining: Option<Duration>) -> i32 {
+    match remaining {
+        Some(remaining) => {
+            let millis = remaining.as_micros().div_ceil(1000);
+            millis.min(POLL_INTERVAL_MS as u128) as i32
+        }
+        None => POLL_INTERVAL_MS,
+    }
+}
+
+/// Reads edges from the line until told to stop. With `debounce`, an edge
+/// is only accepted once the line has stayed at its new level for that
+/// long, so that a bouncing contact gives a single edge.
+fn monitor(
+    handle: &cdev::LineEventHandle,
+    shared: &Shared,
+    reported: Option<Edges>,
+    debounce: Option<Duration>,
+) {
+    let fd = handle.as_raw_fd();
+    let mut debouncer = debounce.map(|debounce| Debouncer::new(debounce, shared.lock().level));
+    while !shared.stop.load(Ordering::Relaxed) {
+        let remaining = debouncer.as_ref().and_then(|d| d.remaining(Instant::now()));
+        let timeout = poll_timeout(remaining);
+        let mut fds = [PollFd::new(fd, EventFlags::POLLIN)];
+        let result = match poll(&mut fds, timeout) {
+            Ok(0) => Ok(None),
+            Ok(_) => handle.get_event().map(Some).map_err(|e| e.to_string()),
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(e) => Err(e.to_string()),
+        };
+        let mut queue = shared.lock();
+        match result {
+            Ok(Some(event)) => {
+                let edge = Edge {
+                    timestamp: event.timestamp(),
+                    rising: event.event_type() == cdev::EventType::RisingEdge,
+                };
+                match &mut debouncer {
+                    Some(debouncer) => debouncer.edge(edge, Instant::now()),
+                    None => queue.push(edge, reported),
+                }
+            }
+            Ok(None) => (),
+            Err(failure) => {
+                log::error!("Stopped reading edge events: {}", failure);
+                queue.failure = Some(failure);
+                shared.changed.notify_all();
+                return;
+            }
+        }
+        if let Some(edge) = debouncer.as_mut().and_then(|d| d.settle(Instant::now())) {
+            queue.level = edge.rising as u8;
+            queue.push(edge, reported);
+        }
+        shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Edges at the given times in microseconds, starting with a rising
+    /// edge and alternating.
+    fn alternating(times: &[u64]) -> Vec<Edge> {
+        times
+            .iter()
+            .enumerate()
+            .map(|(i, &time)| Edge {
+                timestamp: time * 1000,
+                rising: i % 2 == 0,
+            })
+            .collect()
+    }
+
+    fn measure(edges: &[Edge]) -> json::Value {
+        let mut measurement = Measurement::new(false);
+        for &edge in edges {
+            measurement.add(edge);
+        }
+        measurement.to_json()
+    }
+
+    #[test]
+    fn fewer_than_two_edges_give_no_measurements() {
+        for edges in &[vec![], alternating(&[100])] {
+            let measurement = measure(edges);
+            assert_eq!(measurement["edges"], edges.len());
+            assert!(measurement["frequency"].is_null());
+            assert!(measurement["duty-cycle"].is_null());
+            assert!(measurement["high-time"].is_null());
+            assert!(measurement["low-time"].is_null());
+        }
+    }
+
+    #[test]
+    fn rising_edges_alone_give_only_the_frequency() {
+        let edges: Vec<Edge> = (0..4)
+            .map(|i| Edge {
+                timestamp: i * 1_000_000,
+                rising: true,
+            })
+            .collect();
+        let measurement = measure(&edges);
+        assert_eq!(measurement["rising"], 4);
+        assert_eq!(measurement["falling"], 0);
+        assert_eq!(measurement["frequency"], 1000.0);
+        assert!(measurement["duty-cycle"].is_null());
+        assert!(measurement["high-time"].is_null());
+        assert!(measurement["low-time"].is_null());
+    }
+
+    #[test]
+    fn uneven_duty_cycles_are_measured() {
+        let measurement = measure(&alternating(&[0, 250, 1000, 1250, 2000]));
+        assert_eq!(measurement["frequency"], 1000.0);
+        assert_eq!(measurement["duty-cycle"], 0.25);
+        assert_eq!(
+            measurement["high-time"],
+            json::json!({ "min": 250_000, "max": 250_000 })
+        );
+        assert_eq!(
+            measurement["low-time"],
+            json::json!({ "min": 750_000, "max": 750_000 })
+        );
+    }
+
+    /// Feeds a debouncer edges read at the given times in milliseconds,
+    /// checking for settled edges every millisecond until `end`, and
+    /// returns the accepted edges with when they were accepted.
+    fn debounce(debounce_ms: u64, edges: &[(u64, bool)], end: u64) -> Vec<(u64, Edge)> {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut debouncer = Debouncer::new(Duration::from_millis(debounce_ms), 0);
+        let mut accepted = Vec::new();
+        for ms in 0..=end {
+            for &(time, rising) in edges.iter().filter(|(time, _)| *time == ms) {
+                let edge = Edge {
+                    timestamp: time,
+                    rising,
+                };
+                debouncer.edge(edge, at(ms));
+            }
+            if let Some(edge) = debouncer.settle(at(ms)) {
+                accepted.push((ms, edge));
+            }
+        }
+        accepted
+    }
+
+    #[test]
+    fn a_bouncing_change_is_accepted_once_with_its_last_transition() {
+        let bounces = [(0, true), (1, false), (2, true), (3, false), (4, true)];
+        let accepted = debounce(10, &bounces, 30);
+        assert_eq!(accepted.len(), 1);
+        let (when, edge) = accepted[0];
+        assert_eq!(when, 14);
+        assert!(edge.rising);
+        assert_eq!(edge.timestamp, 4);
+    }
+
+    #[test]
+    fn a_glitch_shorter_than_the_debounce_time_is_ignored() {
+        let glitch = [(0, true), (5, false)];
+        assert!(debounce(10, &glitch, 30).is_empty());
+    }
+
+    #[test]
+    fn changes_which_settle_are_each_accepted() {
+        let changes = [(0, true), (20, false), (22, true), (24, false)];
+        let accepted: Vec<(u64, bool, u64)> = debounce(10, &changes, 50)
+            .into_iter()
+            .map(|(when, edge)| (when, edge.rising, edge.timestamp))
+            .collect();
+        assert_eq!(accepted, vec![(10, true, 0), (34, false, 24)]);
+    }
+
+    #[test]
+    fn repeated_edges_in_one_direction_restart_the_wait() {
+        let missed = [(0, true), (6, true)];
+        let accepted = debounce(10, &missed, 30);
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].0, 16);
+        assert_eq!(accepted[0].1.timestamp, 6);
+    }
+
+    fn rising(timestamp: u64) -> Edge {
+        Edge {
+            timestamp,
+            rising: true,
+        }
+    }
+
+    #[test]
+    fn only_reported_edges_are_queued() {
+        let mut queue = Queue::new(4, 0);
+        let falling = Edge {
+            timestamp: 2,
+            rising: false,
+        };
+        queue.push(rising(1), Some(Edges::Rising));
+        queue.push(falling, Some(Edges::Rising));
+        queue.push(falling, None);
+        assert_eq!(queue.received, 1);
+        let (edges, dropped) = queue.take();
+        assert_eq!(edges.iter().map(|e| e.timestamp).collect::<Vec<_>>(), [1]);
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn the_oldest_edges_are_dropped_and_counted_until_taken() {
+        let mut queue = Queue::new(3, 0);
+        for timestamp in 1..=5 {
+            queue.push(rising(timestamp), Some(Edges::Both));
+        }
+        let (edges, dropped) = queue.take();
+        assert_eq!(
+            edges.iter().map(|e| e.timestamp).collect::<Vec<_>>(),
+            [3, 4, 5]
+        );
+        assert_eq!(dropped, 2);
+        queue.push(rising(6), Some(Edges::Both));
+        let (edges, dropped) = queue.take();
+        assert_eq!(edges.len(), 1);
+        assert_eq!(dropped, 0);
+        assert_eq!(queue.received, 6);
+    }
+
+    #[test]
+    fn edges_since_a_point_are_found_from_the_back_of_the_queue() {
+        let mut queue = Queue::new(3, 0);
+        let timestamps = |queue: &Queue, start| -> Vec<u64> {
+            queue.since(start).map(|e| e.timestamp).collect()
+        };
+        queue.push(rising(1), Some(Edges::Both));
+        let start = queue.received;
+        assert!(timestamps(&queue, start).is_empty());
+        queue.push(rising(2), Some(Edges::Both));
+        assert_eq!(timestamps(&queue, start), [2]);
+        // Edges taken in the meantime are no longer there to be found.
+        queue.take();
+        queue.push(rising(3), Some(Edges::Both));
+        assert_eq!(timestamps(&queue, start), [3]);
+        // Nor are edges dropped in the meantime.
+        for timestamp in 4..=6 {
+            queue.push(rising(timestamp), Some(Edges::Both));
+        }
+        assert_eq!(timestamps(&queue, start), [4, 5, 6]);
+    }
+
+    #[test]
+    fn measurements_cover_more_edges_than_the_queue_holds() {
+        let mut queue = Queue::new(4, 0);
+        queue.measurement = Some(Measurement::new(true));
+        // A 1 kHz clock for 10 periods.
+        for edge in alternating(&(0..20).map(|i| i * 500).collect::<Vec<_>>()) {
+            queue.push(edge, Some(Edges::Both));
+        }
+        let measurement = queue.measurement.take().unwrap().to_json();
+        assert_eq!(measurement["edges"], 20);
+        assert_eq!(measurement["frequency"], 1000.0);
+        assert_eq!(measurement["missed"], 0);
+        assert_eq!(queue.take().1, 16);
+    }
+
+    #[test]
+    fn edges_missing_between_two_of_the_same_kind_are_counted() {
+        let mut measurement = Measurement::new(true);
+        let times = [
+            (0, true),
+            (500, false),
+            (1000, true),
+            (2000, true),
+            (2500, false),
+        ];
+        for &(time, rising) in &times {
+            measurement.add(Edge {
+                timestamp: time * 1000,
+                rising,
+            });
+        }
+        let measurement = measurement.to_json();
+        assert_eq!(measurement["missed"], 1);
+        assert_eq!(
+            measurement["high-time"],
+            json::json!({ "min": 500000, "max": 500000 })
+        );
+        assert!(measure(&alternating(&[0, 500]))["missed"].is_null());
+    }
+
+    #[test]
+    fn the_poll_timeout_is_rounded_up_and_capped() {
+        assert_eq!(poll_timeout(None), POLL_INTERVAL_MS);
+        assert_eq!(poll_timeout(Some(Duration::from_micros(1))), 1);
+        assert_eq!(poll_timeout(Some(Duration::from_micros(2500))), 3);
+        let long = crate::duration::parse_duration("40000min").unwrap();
+        let mut debouncer = Debouncer::new(long, 0);
+        let now = Instant::now();
+        let edge = Edge {
+            timestamp: 0,
+            rising: true,
+        };
+        debouncer.edge(edge, now);
+        assert_eq!(poll_timeout(debouncer.remaining(now)), POLL_INTERVAL_MS);
+        assert_eq!(poll_timeout(Some(Duration::MAX)), POLL_INTERVAL_MS);
+    }
+
+    #[test]
+    fn an_unrepresentable_debounce_time_never_settles() {
+        let mut debouncer = Debouncer::new(Duration::MAX, 0);
+        let now = Instant::now();
+        let edge = Edge {
+            timestamp: 0,
+            rising: true,
+        };
+        debouncer.edge(edge, now);
+        assert_eq!(debouncer.remaining(now), None);
+        assert!(debouncer.settle(now + Duration::from_secs(3600)).is_none());
+    }
+
+    #[test]
+    fn pulse_widths_are_given_as_a_range() {
+        let measurement = measure(&alternating(&[0, 100, 1000, 1300, 2000, 2200]));
+        assert_eq!(
+            measurement["high-time"],
+            json::json!({ "min": 100_000, "max": 300_000 })
+        );
+        assert_eq!(
+            measurement["low-time"],
+            json::json!({ "min": 700_000, "max": 900_000 })
+        );
+        assert_eq!(measurement["duty-cycle"], 600.0 / 2200.0);
+    }
+}
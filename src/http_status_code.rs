@@ -7,7 +7,10 @@ pub enum HTTPStatusCode {
     Forbidden,
     NotFound,
     MethodNotAllowed,
-    NotImplemented,
+    Conflict,
+    InternalServerError,
+    ServiceUnavailable,
+    GatewayTimeout,
 }
 
 impl HTTPStatusCode {
@@ -19,7 +22,10 @@ impl HTTPStatusCode {
             Forbidden => 403,
             NotFound => 404,
             MethodNotAllowed => 405,
-            NotImplemented => 501,
+            Conflict => 409,
+            InternalServerError => 500,
+            ServiceUnavailable => 503,
+            GatewayTimeout => 504,
         }
     }
 
@@ -31,7 +37,10 @@ impl HTTPStatusCode {
             Forbidden => "Forbidden",
             NotFound => "Not found",
             MethodNotAllowed => "Method not allowed",
-            NotImplemented => "Not implemented",
+            Conflict => "Conflict",
+            InternalServerError => "Internal server error",
+            ServiceUnavailable => "Service unavailable",
+            GatewayTimeout => "Gateway timeout",
         }
     }
 }
@@ -5,17 +5,27 @@
  * Copyright (C) 2020 Callum David O'Brien
  */
 
+mod duration;
+mod edge_events;
 mod http_status_code;
 mod response_data;
+mod pattern;
+mod pin_sequence;
 mod port_settings_from_json;
+mod serial_capture;
 
+pub mod config_error;
 pub mod limb;
 pub mod pin;
+pub mod pwm;
 pub mod serial;
+pub mod soft_pwm;
 pub mod xmodem;
 pub mod server;
 
 extern crate gpio_cdev;
+extern crate log;
+extern crate nix;
 extern crate serde_json;
 extern crate serial as system_serial;
 extern crate tiny_http;
@@ -7,81 +7,745 @@
 
 use std::{
     collections::HashMap,
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
 };
 
 use serde_json as json;
 
-#[derive(Debug)]
-pub enum Error {
+pub use crate::config_error::ConfigError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
     BrokenLimb,
     InvalidValue,
+    /// The resource can't be used with the request's method at all. Limb
+    /// resources only take GET and POST, so the other method is reported
+    /// as the one allowed. An operation which can't be done yet is `Busy`.
     InvalidOperation,
     WriteFailed,
     ReadFailed,
     Timeout,
+    Busy,
+    NoSuchResource,
 }
 
-impl Into<&'static str> for Error {
-    fn into(self) -> &'static str {
-        use Error::*;
-        match self {
+impl From<ErrorKind> for &'static str {
+    fn from(kind: ErrorKind) -> Self {
+        use ErrorKind::*;
+        match kind {
             BrokenLimb => "Broken limb",
             InvalidValue => "Invalid value",
             InvalidOperation => "Invalid operation",
             WriteFailed => "Write failed",
             ReadFailed => "Read failed",
             Timeout => "Timeout",
+            Busy => "Busy",
+            NoSuchResource => "No such resource",
+        }
+    }
+}
+
+/// A failed limb operation: what kind of failure it was, a description of
+/// what was being done, the error underneath (usually from the OS) and the
+/// limb it happened to.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    message: Option<String>,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    limb: Option<String>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: Some(message.into()),
+            source: None,
+            limb: None,
+        }
+    }
+
+    /// Records the error which caused the operation to fail.
+    pub fn with_source(
+        mut self,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Records the name of the limb the operation was on.
+    pub fn in_limb(mut self, limb: &str) -> Self {
+        self.limb = Some(limb.to_owned());
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    pub fn limb(&self) -> Option<&str> {
+        self.limb.as_deref()
+    }
+
+    /// An error caused by an I/O error, with timeouts kept distinct.
+    pub fn from_io(kind: ErrorKind, message: impl Into<String>, source: io::Error) -> Self {
+        let kind = match source.kind() {
+            io::ErrorKind::TimedOut => ErrorKind::Timeout,
+            _ => kind,
+        };
+        Self::new(kind, message).with_source(source)
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            message: None,
+            source: None,
+            limb: None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(limb) = &self.limb {
+            write!(f, "{}: ", limb)?;
+        }
+        let kind: &'static str = self.kind.into();
+        f.write_str(kind)?;
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
         }
+        if let Some(source) = &self.source {
+            write!(f, ": {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|e| e.as_ref() as &(dyn std::error::Error + 'static))
     }
 }
 
 pub trait Limb: Send + Sync {
-    fn from_json(config: &json::Value) -> Option<Self>
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError>
     where
         Self: Sized;
+    /// Creates the limb `name` from its configuration. Limbs which use their
+    /// name, e.g. to label the resources they hold, override this.
+    fn from_json_named(_name: &str, config: &json::Value) -> Result<Self, ConfigError>
+    where
+        Self: Sized,
+    {
+        Self::from_json(config)
+    }
     fn set(&mut self, value: String) -> Result<(), Error>;
     fn get(&mut self) -> Result<String, Error>;
     fn type_name(&self) -> &'static str;
+    /// Reads a resource of the limb, i.e. handles a GET of
+    /// `/limb/<name>/<resource>`. The limb's own value is the resource "".
+    fn get_resource(&mut self, resource: &str, _query: &Query) -> Result<json::Value, Error> {
+        match resource {
+            "" => self.get().map(json::Value::String),
+            _ => Err(no_such_resource(resource)),
+        }
+    }
+    /// Writes to a resource of the limb, i.e. handles a POST of
+    /// `/limb/<name>/<resource>`, returning a value to respond with if the
+    /// write produces one.
+    fn set_resource(
+        &mut self,
+        resource: &str,
+        value: String,
+        _query: &Query,
+    ) -> Result<Option<json::Value>, Error> {
+        match resource {
+            "" => self.set(value).map(|_| None),
+            _ => Err(no_such_resource(resource)),
+        }
+    }
+    /// Reads a resource of the limb as raw bytes, for GET requests which
+    /// accept `application/octet-stream`. Limbs without binary resources
+    /// send the text of the value from `get_resource`.
+    fn get_resource_bytes(&mut self, resource: &str, query: &Query) -> Result<Vec<u8>, Error> {
+        self.get_resource(resource, query).map(value_bytes)
+    }
+    /// Writes raw bytes to a resource of the limb, for POST requests with an
+    /// `application/octet-stream` body. Limbs without binary resources take
+    /// the bytes as text, rejecting them if they aren't UTF-8.
+    fn set_resource_bytes(
+        &mut self,
+        resource: &str,
+        value: Vec<u8>,
+        query: &Query,
+    ) -> Result<Option<json::Value>, Error> {
+        let value = String::from_utf8(value).map_err(|e| {
+            Error::new(ErrorKind::InvalidValue, "expected UTF-8 text").with_source(e)
+        })?;
+        self.set_resource(resource, value, query)
+    }
+    /// Details about the value last read by `get_resource`, such as how much
+    /// data was lost before it. They are sent as `X-Phal-<Name>` headers in
+    /// text mode, and beside the value in JSON mode.
+    fn annotations(&mut self) -> json::Map<String, json::Value> {
+        json::Map::new()
+    }
+    /// The limb's own configuration parameters, in the same form accepted by
+    /// `from_json`. The `type` and `init` members are added by `LimbBindings`.
+    fn to_json(&self) -> json::Value;
+    /// The values which optional configuration parameters take when they
+    /// are left out, as a JSON object. A configuration which only differs
+    /// from the current one by spelling these out, or leaving them out, is
+    /// unchanged, and so doesn't reopen the limb.
+    fn config_defaults() -> json::Value
+    where
+        Self: Sized,
+    {
+        json::json!({})
+    }
+}
+
+/// The error for a request to a resource which a limb doesn't have.
+pub fn no_such_resource(resource: &str) -> Error {
+    Error::new(
+        ErrorKind::NoSuchResource,
+        format!("no resource named \"{}\"", resource),
+    )
+}
+
+/// The bytes sent for a value read from a limb: strings as they are, and
+/// any other value as a JSON document.
+pub fn value_bytes(value: json::Value) -> Vec<u8> {
+    match value {
+        json::Value::String(s) => s.into_bytes(),
+        value => value.to_string().into_bytes(),
+    }
 }
 
-type LimbTypesHashMapKey = Box<dyn Fn(&serde_json::Value) -> Option<Box<dyn Limb>>>;
-type LimbTypesHashMap = HashMap<String, LimbTypesHashMapKey>;
+/// The parameters given in the query string of a request to a limb, e.g.
+/// `?timeout=2s&async`.
+#[derive(Debug, Default, Clone)]
+pub struct Query(HashMap<String, String>);
+
+impl Query {
+    /// Parses a query string, without its leading `?`.
+    pub fn parse(query: &str) -> Self {
+        let parameters = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| match p.find('=') {
+                Some(i) => (percent_decode(&p[..i]), percent_decode(&p[i + 1..])),
+                None => (percent_decode(p), String::new()),
+            })
+            .collect();
+        Query(parameters)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.contains_key(key)
+    }
+
+    /// The parameter `key` as a duration such as `500ms`, if it was given.
+    pub fn duration(&self, key: &str) -> Result<Option<Duration>, Error> {
+        match self.get(key) {
+            Some(value) => crate::duration::parse_duration(value)
+                .map(Some)
+                .ok_or_else(|| {
+                    let message = format!("expected a duration such as 500ms for {}", key);
+                    Error::new(ErrorKind::InvalidValue, message)
+                }),
+            None => Ok(None),
+        }
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = match (byte, tail) {
+            (b'%', [high, low, ..]) => std::str::from_utf8(&[*high, *low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(if byte == b'+' { b' ' } else { byte });
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+type LimbConstructor =
+    Box<dyn Fn(&str, &serde_json::Value) -> Result<Box<dyn Limb>, ConfigError> + Send + Sync>;
+type LimbTypesHashMap = HashMap<String, LimbType>;
+
+/// How to create limbs of one type, and the defaults of their
+/// configurations.
+pub struct LimbType {
+    constructor: LimbConstructor,
+    defaults: json::Value,
+}
+
+impl LimbType {
+    pub fn of<L: Limb + 'static>() -> Self {
+        LimbType {
+            constructor: Box::new(|name, config| {
+                L::from_json_named(name, config).map(|l| {
+                    let limb: Box<dyn Limb> = Box::new(l);
+                    limb
+                })
+            }),
+            defaults: L::config_defaults(),
+        }
+    }
+}
 
 pub struct LimbTypes(LimbTypesHashMap);
 
-pub struct LimbBindings(HashMap<String, Box<dyn Limb>>);
+/// A limb which can be used after the lock on the bindings it came from has
+/// been released, so that a long operation on one limb doesn't hold up
+/// configuration changes. The limb is gone once it has been released by
+/// its bindings, e.g. because it was reconfigured in the meantime.
+#[derive(Clone)]
+pub struct SharedLimb(Arc<Mutex<Option<Box<dyn Limb>>>>);
+
+impl SharedLimb {
+    /// Locks the limb, waiting for any other user of it to finish. A limb
+    /// which panicked while locked is still handed out, as the panic is
+    /// reported to the client that caused it.
+    pub fn lock(&self) -> MutexGuard<'_, Option<Box<dyn Limb>>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sets the limb `name` back to `init_value`, unless it has been
+    /// released.
+    fn reinit(&self, name: &str, init_value: &str) -> Result<(), ConfigError> {
+        let mut limb = self.lock();
+        let limb = match limb.as_mut() {
+            Some(limb) => limb,
+            None => return Ok(()),
+        };
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            apply_init(name, limb.as_mut(), init_value)
+        }))
+        .unwrap_or_else(|_| {
+            let expected = "a value the limb accepts (setting it panicked)";
+            Err(ConfigError::new("init", expected).in_limb(name))
+        })
+    }
+}
+
+/// A configured limb, along with its configuration. The limb is behind its
+/// own lock, so that different limbs can be used at the same time while
+/// each limb is only used by one request at a time.
+struct BoundLimb {
+    config: json::Value,
+    /// The configuration as it was given, which may spell its parameters
+    /// differently to `config`.
+    given: json::Value,
+    limb_type: &'static str,
+    limb: SharedLimb,
+}
+
+impl BoundLimb {
+    /// Creates a limb, turning a panic while doing so into an error, so that
+    /// a limb with a bug in it can't lose the limbs being reconfigured
+    /// alongside it.
+    fn from_json(name: &str, config: &json::Value, types: &LimbTypes) -> Result<Self, ConfigError> {
+        panic::catch_unwind(AssertUnwindSafe(|| Self::create(name, config, types))).unwrap_or_else(
+            |_| {
+                let expected = "a configuration the limb can be created from (it panicked)";
+                Err(ConfigError::root(expected).in_limb(name))
+            },
+        )
+    }
+
+    fn create(name: &str, config: &json::Value, types: &LimbTypes) -> Result<Self, ConfigError> {
+        if !config.is_object() {
+            return Err(ConfigError::root("a JSON object").in_limb(name));
+        }
+        let type_name = match &config["type"] {
+            json::Value::String(s) => Ok(s),
+            _ => Err(ConfigError::new("type", "the name of a limb type")),
+        }
+        .map_err(|e| e.in_limb(name))?;
+        let constructor = types.0.get(type_name).ok_or_else(|| {
+            let mut names: Vec<&str> = types.names().map(String::as_str).collect();
+            names.sort_unstable();
+            let expected = format!("one of the limb types {}", names.join(", "));
+            ConfigError::new("type", &expected).in_limb(name)
+        })?;
+        let mut limb = (constructor.constructor)(name, config).map_err(|e| e.in_limb(name))?;
+        let init = match &config["init"] {
+            json::Value::String(init_value) => {
+                apply_init(name, limb.as_mut(), init_value)?;
+                Some(init_value.to_string())
+            }
+            _ => None,
+        };
+        let mut limb_config = match limb.to_json() {
+            json::Value::Object(o) => o,
+            _ => json::Map::new(),
+        };
+        limb_config.insert("type".to_owned(), json::Value::String(type_name.clone()));
+        if let Some(init) = init {
+            limb_config.insert("init".to_owned(), json::Value::String(init));
+        }
+        Ok(BoundLimb {
+            config: json::Value::Object(limb_config),
+            given: config.clone(),
+            limb_type: limb.type_name(),
+            limb: SharedLimb(Arc::new(Mutex::new(Some(limb)))),
+        })
+    }
+
+    fn to_json(&self) -> json::Value {
+        self.config.clone()
+    }
+
+    /// Whether `config` would create the same limb, either because it is
+    /// the configuration the limb was created from or because it is the
+    /// same once both are normalized.
+    fn is_configured_by(&self, config: &json::Value, types: &LimbTypes) -> bool {
+        let config = normalize(config, types);
+        config == normalize(&self.config, types) || config == normalize(&self.given, types)
+    }
+}
+
+impl Drop for BoundLimb {
+    /// Closes the limb as soon as any request using it has finished, rather
+    /// than when the last request holding on to it lets go, so that its
+    /// resources can be reopened straight away.
+    fn drop(&mut self) {
+        self.limb.lock().take();
+    }
+}
+
+fn apply_init(name: &str, limb: &mut dyn Limb, init_value: &str) -> Result<(), ConfigError> {
+    limb.set(init_value.to_owned()).map_err(|error| {
+        let expected = format!("a value the limb accepts ({})", error);
+        ConfigError::new("init", &expected).in_limb(name)
+    })
+}
+
+/// A limb's configuration with the defaults of its type filled in and any
+/// `null` members left out, so that different spellings of the same
+/// configuration compare equal.
+fn normalize(config: &json::Value, types: &LimbTypes) -> json::Value {
+    let members = match config {
+        json::Value::Object(o) => o,
+        _ => return config.clone(),
+    };
+    let mut normalized = match config["type"].as_str().and_then(|t| types.0.get(t)) {
+        Some(limb_type) => match &limb_type.defaults {
+            json::Value::Object(defaults) => defaults.clone(),
+            _ => json::Map::new(),
+        },
+        None => json::Map::new(),
+    };
+    for (key, value) in members {
+        if value.is_null() {
+            normalized.remove(key);
+        } else {
+            normalized.insert(key.clone(), normalize_numbers(value));
+        }
+    }
+    json::Value::Object(normalized)
+}
+
+/// Writes whole numbers as integers, so that e.g. `1000.0` equals `1000`.
+fn normalize_numbers(value: &json::Value) -> json::Value {
+    match value {
+        json::Value::Number(n) if !(n.is_u64() || n.is_i64()) => match n.as_f64() {
+            Some(f) if f.fract() == 0.0 && f.abs() < 9e15 => json::json!(f as i64),
+            _ => value.clone(),
+        },
+        json::Value::Array(a) => a.iter().map(normalize_numbers).collect(),
+        json::Value::Object(o) => json::Value::Object(
+            o.iter()
+                .map(|(k, v)| (k.clone(), normalize_numbers(v)))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+/// A change to the limbs, as requested of the server.
+pub enum ConfigChange<'a> {
+    /// Replaces the whole configuration.
+    Reconfigure(&'a str),
+    /// Adds or replaces the named limb.
+    Insert(&'a str, &'a str),
+    /// Merges a partial configuration, in which `null` removes a limb.
+    Patch(&'a str),
+    /// Removes the named limb.
+    Remove(&'a str),
+}
+
+/// A change which has been started with `LimbBindings::begin`, holding the
+/// limbs it releases and the limbs it sets back to their `init` values.
+pub struct PendingChange {
+    config: json::Map<String, json::Value>,
+    released: Vec<BoundLimb>,
+    /// The configurations of the released limbs, to reopen them from if the
+    /// change fails.
+    previous: json::Map<String, json::Value>,
+    resets: Vec<(String, SharedLimb, String)>,
+    errors: Vec<ConfigError>,
+}
+
+impl PendingChange {
+    /// Closes the released limbs and resets the retained ones, waiting for
+    /// any requests using them to finish.
+    pub fn release(&mut self) {
+        self.released.clear();
+        for (name, limb, init_value) in self.resets.drain(..) {
+            self.errors.extend(limb.reinit(&name, &init_value).err());
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LimbBindings(HashMap<String, BoundLimb>);
 
 impl LimbBindings {
     pub fn new() -> Self {
         LimbBindings(HashMap::new())
     }
 
-    pub fn iter(&self) -> std::collections::hash_map::Iter<String, Box<dyn Limb>> {
-        self.0.iter()
+    /// The name of each limb, along with the `Limb::type_name` of the limb.
+    pub fn type_names(&self) -> impl Iterator<Item = (&String, &'static str)> {
+        self.0.iter().map(|(k, v)| (k, v.limb_type))
     }
 
-    pub fn from_json(json: &str, types: &LimbTypes) -> Option<Self> {
+    /// The configuration which produced these bindings, as accepted by
+    /// `from_json`.
+    pub fn to_json(&self) -> json::Value {
+        json::Value::Object(self.config_map())
+    }
+
+    /// Binds every limb in `json`, reporting every limb which could not be
+    /// bound.
+    pub fn from_json(json: &str, types: &LimbTypes) -> Result<Self, Vec<ConfigError>> {
+        let config = parse_config(json)?;
         let mut limbs = HashMap::new();
-        match json::from_str(json).ok()? {
-            json::Value::Object(o) => {
-                for (k, v) in o.iter() {
-                    let mut limb = match &v["type"] {
-                        json::Value::String(s) => types.0[s](v),
-                        _ => None,
-                    }?;
-                    if let json::Value::String(init_value) = &v["init"] {
-                        limb.set(init_value.to_string()).ok()?;
+        let mut errors = Vec::new();
+        for (k, v) in config.iter() {
+            match BoundLimb::from_json(k, v, types) {
+                Ok(bound) => {
+                    limbs.insert(String::from(k), bound);
+                }
+                Err(error) => errors.push(error),
+            }
+        }
+        if errors.is_empty() {
+            Ok(LimbBindings(limbs))
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Applies a new configuration as a single transaction: either every
+    /// limb in `json` is bound, or the existing bindings are left in place.
+    /// Limbs whose configuration is unchanged are handed over as-is, and
+    /// set back to their `init` value.
+    pub fn reconfigure(&mut self, json: &str, types: &LimbTypes) -> Result<(), Vec<ConfigError>> {
+        self.change(ConfigChange::Reconfigure(json), types)
+    }
+
+    /// Adds the limb `name`, or replaces it if it already exists, leaving
+    /// every other limb untouched.
+    pub fn insert(
+        &mut self,
+        name: &str,
+        json: &str,
+        types: &LimbTypes,
+    ) -> Result<(), Vec<ConfigError>> {
+        self.change(ConfigChange::Insert(name, json), types)
+    }
+
+    /// Merges a partial configuration into the current one: each member
+    /// adds or replaces a limb, except `null` members, which remove one.
+    pub fn patch(&mut self, json: &str, types: &LimbTypes) -> Result<(), Vec<ConfigError>> {
+        self.change(ConfigChange::Patch(json), types)
+    }
+
+    /// Makes a change in one go, waiting for the limbs it releases while
+    /// the bindings are borrowed.
+    pub fn change(
+        &mut self,
+        change: ConfigChange,
+        types: &LimbTypes,
+    ) -> Result<(), Vec<ConfigError>> {
+        let mut pending = self.begin(change, types)?;
+        pending.release();
+        self.finish(pending, types)
+    }
+
+    /// Starts making a change, taking the limbs it replaces or removes out
+    /// of the bindings. The change is made by calling `release` on the
+    /// result, which can be done without these bindings locked, and then
+    /// `finish`. Other changes mustn't be made in the meantime.
+    ///
+    /// Limbs hold exclusive resources (GPIO lines, serial ports), so a limb
+    /// which is reconfigured must be released before its replacement can be
+    /// opened.
+    pub fn begin(
+        &mut self,
+        change: ConfigChange,
+        types: &LimbTypes,
+    ) -> Result<PendingChange, Vec<ConfigError>> {
+        let (config, reinit) = match change {
+            ConfigChange::Reconfigure(json) => (parse_config(json)?, true),
+            ConfigChange::Insert(name, json) => {
+                let limb_config = json::from_str(json).map_err(|error| {
+                    let expected = format!("valid JSON ({})", error);
+                    vec![ConfigError::root(&expected).in_limb(name)]
+                })?;
+                let mut config = self.config_map();
+                config.insert(name.to_owned(), limb_config);
+                (config, false)
+            }
+            ConfigChange::Patch(json) => {
+                let mut config = self.config_map();
+                for (name, limb_config) in parse_config(json)? {
+                    if limb_config.is_null() {
+                        config.remove(&name);
+                    } else {
+                        config.insert(name, limb_config);
+                    }
+                }
+                (config, false)
+            }
+            ConfigChange::Remove(name) => {
+                let mut config = self.config_map();
+                if config.remove(name).is_none() {
+                    return Err(vec![ConfigError::root("a configured limb").in_limb(name)]);
+                }
+                (config, false)
+            }
+        };
+
+        let mut pending = PendingChange {
+            config: json::Map::new(),
+            released: Vec::new(),
+            previous: json::Map::new(),
+            resets: Vec::new(),
+            errors: Vec::new(),
+        };
+        let mut retained = HashMap::new();
+        for (name, bound) in self.0.drain() {
+            match config.get(&name) {
+                Some(limb_config) if bound.is_configured_by(limb_config, types) => {
+                    if let (true, json::Value::String(init)) = (reinit, &bound.config["init"]) {
+                        pending
+                            .resets
+                            .push((name.clone(), bound.limb.clone(), init.clone()));
                     }
-                    limbs.insert(String::from(k), limb);
+                    retained.insert(name, bound);
+                }
+                _ => {
+                    pending.previous.insert(name, bound.to_json());
+                    pending.released.push(bound);
                 }
             }
-            _ => return None,
         }
-        Some(LimbBindings(limbs))
+        self.0 = retained;
+        pending.config = config;
+        Ok(pending)
     }
 
-    pub fn get(&mut self, name: &str) -> Option<&mut Box<dyn Limb>> {
-        self.0.get_mut(name)
+    /// Opens the limbs added by a change which has been released. If any of
+    /// them fails, or a limb couldn't be set back to its `init` value, the
+    /// limbs opened are closed again and the released limbs are reopened
+    /// from their previous configuration.
+    pub fn finish(
+        &mut self,
+        pending: PendingChange,
+        types: &LimbTypes,
+    ) -> Result<(), Vec<ConfigError>> {
+        let PendingChange {
+            config,
+            released,
+            previous,
+            mut errors,
+            ..
+        } = pending;
+        drop(released);
+
+        let mut opened = HashMap::new();
+        for (name, limb_config) in config.iter() {
+            if self.0.contains_key(name) {
+                continue;
+            }
+            match BoundLimb::from_json(name, limb_config, types) {
+                Ok(bound) => {
+                    opened.insert(name.clone(), bound);
+                }
+                Err(error) => errors.push(error),
+            }
+        }
+
+        if errors.is_empty() {
+            self.0.extend(opened);
+            Ok(())
+        } else {
+            drop(opened);
+            for (name, previous) in previous.iter() {
+                match BoundLimb::from_json(name, previous, types) {
+                    Ok(bound) => {
+                        self.0.insert(name.clone(), bound);
+                    }
+                    Err(error) => log::error!("Failed to restore limb: {}", error),
+                }
+            }
+            Err(errors)
+        }
+    }
+
+    /// Releases the limb `name`, returning whether it existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.0.remove(name).is_some()
+    }
+
+    fn config_map(&self) -> json::Map<String, json::Value> {
+        self.0
+            .iter()
+            .map(|(k, v)| (k.clone(), v.to_json()))
+            .collect()
+    }
+
+    /// The limb `name`, which can be locked once these bindings are no
+    /// longer needed.
+    pub fn get(&self, name: &str) -> Option<SharedLimb> {
+        self.0.get(name).map(|b| b.limb.clone())
     }
 
     pub fn clear(&mut self) {
@@ -89,12 +753,23 @@ impl LimbBindings {
     }
 }
 
+fn parse_config(json: &str) -> Result<json::Map<String, json::Value>, Vec<ConfigError>> {
+    match json::from_str(json) {
+        Ok(json::Value::Object(o)) => Ok(o),
+        Ok(_) => Err(vec![ConfigError::root("a JSON object of limbs")]),
+        Err(error) => {
+            let expected = format!("valid JSON ({})", error);
+            Err(vec![ConfigError::root(&expected)])
+        }
+    }
+}
+
 impl LimbTypes {
     pub fn from(h: LimbTypesHashMap) -> Self {
         LimbTypes(h)
     }
 
-    pub fn names(&self) -> std::collections::hash_map::Keys<String, LimbTypesHashMapKey> {
+    pub fn names(&self) -> std::collections::hash_map::Keys<'_, String, LimbType> {
         self.0.keys()
     }
 }
@@ -103,12 +778,9 @@ impl LimbTypes {
 macro_rules! limb_types {
 	( $( ($x:expr, $y:ty) ), * ) => {
 		{
-			let mut types: HashMap<String, Box<dyn Fn(&serde_json::Value) -> Option<Box<dyn Limb>>>> = HashMap::new();
+			let mut types: HashMap<String, $crate::limb::LimbType> = HashMap::new();
 			$(
-				types.insert(String::from($x), Box::new(|v| <$y>::from_json(v).map(|l| {
-					let limb: Box<dyn Limb> = Box::new(l);
-					limb
-				})));
+				types.insert(String::from($x), $crate::limb::LimbType::of::<$y>());
 			)*
 				LimbTypes::from(types)
 		}
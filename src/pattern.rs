@@ -0,0 +1,84 @@
+// Copyright (C) 2020 Arron Speake
+
+use regex::bytes::{Regex, RegexBuilder};
+
+/// The most memory a compiled pattern may use, so that a request can't make
+/// the server build an enormous program, e.g. from nested repetitions.
+const SIZE_LIMIT: usize = 1 << 20;
+
+/// How deeply groups and repetitions may be nested in a pattern.
+const NEST_LIMIT: u32 = 64;
+
+/// A pattern to look for in data received from a device, which may not be
+/// valid UTF-8.
+pub struct Pattern(Regex);
+
+impl Pattern {
+    /// A pattern which matches `text` exactly.
+    pub fn literal(text: &str) -> Self {
+        Self::regex(&regex::escape(text)).expect("an escaped literal is a valid regex")
+    }
+
+    /// A regular expression, matched bytewise. `^` and `$` match at the
+    /// start and end of each line, whether lines end with `\n` or `\r\n`.
+    pub fn regex(source: &str) -> Result<Self, String> {
+        RegexBuilder::new(source)
+            .unicode(false)
+            .multi_line(true)
+            .crlf(true)
+            .size_limit(SIZE_LIMIT)
+            .dfa_size_limit(SIZE_LIMIT)
+            .nest_limit(NEST_LIMIT)
+            .build()
+            .map(Pattern)
+            .map_err(|e| e.to_string())
+    }
+
+    /// The start and end of the first match in `data`, if there is one.
+    pub fn find(&self, data: &[u8]) -> Option<(usize, usize)> {
+        self.0.find(data).map(|m| (m.start(), m.end()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pattern;
+
+    fn find(pattern: &str, data: &[u8]) -> Option<(usize, usize)> {
+        Pattern::regex(pattern).unwrap().find(data)
+    }
+
+    #[test]
+    fn literals_are_escaped() {
+        let pattern = Pattern::literal("a.b+");
+        assert_eq!(pattern.find(b"axb a.b+"), Some((4, 8)));
+    }
+
+    #[test]
+    fn anchors_match_at_line_boundaries() {
+        assert_eq!(find(r"^[a-z]+:\s*", b"\r\nlogin: "), Some((2, 9)));
+        assert_eq!(find(r"\d+$", b"v1.25\r\n"), Some((3, 5)));
+    }
+
+    #[test]
+    fn non_utf8_data_is_matched_bytewise() {
+        assert_eq!(find(r"\xff.\x00", b"a\xff\xfe\x00b"), Some((1, 4)));
+    }
+
+    #[test]
+    fn nested_repetition_is_rejected() {
+        assert!(Pattern::regex("(?:(?:a?){1000}){1000}").is_err());
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected() {
+        let source = format!("{}a{}", "(".repeat(1000), ")".repeat(1000));
+        assert!(Pattern::regex(&source).is_err());
+    }
+
+    #[test]
+    fn invalid_syntax_is_rejected() {
+        assert!(Pattern::regex("(a").is_err());
+        assert!(Pattern::regex("a{2,1}").is_err());
+    }
+}
@@ -5,90 +5,962 @@
  * Copyright (C) 2020 Callum David O'Brien
  */
 
-use crate::limb::{Error, Limb};
+use crate::{
+    config_error::io_error_from_cdev,
+    duration::{format_duration, parse_duration},
+    edge_events::{Edge, EdgeMonitor, Edges},
+    limb::{no_such_resource, ConfigError, Error, ErrorKind, Limb, Query},
+    pin_sequence::{parse_sequence, run_sequence},
+};
 
-use std::convert::TryInto;
+use std::{
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
 use gpio_cdev as cdev;
 use serde_json as json;
 
-impl From<cdev::errors::Error> for Error {
-    fn from(_: cdev::errors::Error) -> Self {
-        Self::BrokenLimb
+pub struct OutputPin {
+    handle: Arc<cdev::LineHandle>,
+    config: LineConfig,
+    /// The level the line is driven to as soon as it is requested.
+    initial: Option<u8>,
+    /// A sequence started by an asynchronous request, which may still be
+    /// running, along with the flag which cuts it short.
+    running: Option<(JoinHandle<()>, Arc<AtomicBool>)>,
+}
+
+/// Parses the level of a line, `High` or `Low`.
+pub(crate) fn parse_level(level: &str) -> Option<u8> {
+    match level {
+        "High" => Some(1),
+        "Low" => Some(0),
+        _ => None,
     }
 }
 
-pub struct OutputPin(cdev::LineHandle);
+pub(crate) fn level_name(level: u8) -> &'static str {
+    if level == 0 {
+        "Low"
+    } else {
+        "High"
+    }
+}
 
 impl Limb for OutputPin {
-    fn from_json(config: &json::Value) -> Option<Self> {
-        open_line_handle(config, cdev::LineRequestFlags::OUTPUT).map(|h| OutputPin(h))
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError> {
+        Self::from_json_named("", config)
+    }
+
+    fn from_json_named(name: &str, config: &json::Value) -> Result<Self, ConfigError> {
+        let initial = match &config["initial"] {
+            json::Value::Null => Some(None),
+            json::Value::String(s) => parse_level(s).map(Some),
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("initial", "\"High\" or \"Low\""))?;
+        let config = LineConfig::from_json(name, config)?;
+        let handle = config.request(cdev::LineRequestFlags::OUTPUT, initial.unwrap_or(0))?;
+        Ok(OutputPin {
+            handle: Arc::new(handle),
+            config,
+            initial,
+            running: None,
+        })
     }
 
     fn set(&mut self, value: String) -> Result<(), Error> {
-        match value.as_ref() {
-            "High" => self.0.set_value(1).map_err(|e| e.into()),
-            "Low" => self.0.set_value(0).map_err(|e| e.into()),
-            _ => Err(Error::InvalidValue),
+        self.set_resource("", value, &Query::default()).map(|_| ())
+    }
+
+    /// Runs a sequence of commands (see `parse_sequence`), returning once it
+    /// has finished, or straight away given the `async` query parameter.
+    fn set_resource(
+        &mut self,
+        resource: &str,
+        value: String,
+        query: &Query,
+    ) -> Result<Option<json::Value>, Error> {
+        if !resource.is_empty() {
+            return Err(no_such_resource(resource));
+        }
+        let steps = parse_sequence(&value)
+            .map_err(|message| Error::new(ErrorKind::InvalidValue, message))?;
+        self.wait_for_sequence()?;
+        if query.contains("async") {
+            let handle = Arc::clone(&self.handle);
+            let config = self.config.clone();
+            let cancel = Arc::new(AtomicBool::new(false));
+            let thread = {
+                let cancel = Arc::clone(&cancel);
+                thread::spawn(move || {
+                    if let Err((action, e)) = run_sequence(&handle, &steps, &cancel) {
+                        log::warn!("{}", config.line_error(ErrorKind::WriteFailed, action, e));
+                    }
+                })
+            };
+            self.running = Some((thread, cancel));
+        } else {
+            run_sequence(&self.handle, &steps, &AtomicBool::new(false))
+                .map_err(|(action, e)| self.config.line_error(ErrorKind::WriteFailed, action, e))?;
         }
+        Ok(None)
     }
 
+    /// Reads back the level the line is being driven to.
     fn get(&mut self) -> Result<String, Error> {
-        Err(Error::InvalidOperation)
+        let value = self
+            .handle
+            .get_value()
+            .map_err(|e| self.config.line_error(ErrorKind::ReadFailed, "reading", e))?;
+        Ok(level_name(value).to_owned())
     }
 
     fn type_name(&self) -> &'static str {
         "output-pin"
     }
+
+    fn config_defaults() -> json::Value {
+        LineConfig::defaults()
+    }
+
+    fn to_json(&self) -> json::Value {
+        let mut config = self.config.to_json();
+        if let Some(initial) = self.initial {
+            config["initial"] = json::Value::String(level_name(initial).to_owned());
+        }
+        config
+    }
+}
+
+impl OutputPin {
+    /// Fails if a sequence started asynchronously is still running.
+    fn wait_for_sequence(&mut self) -> Result<(), Error> {
+        match self.running.take() {
+            Some((running, cancel)) if !running.is_finished() => {
+                self.running = Some((running, cancel));
+                Err(Error::new(ErrorKind::Busy, "a sequence is still running"))
+            }
+            Some((running, _)) => {
+                let _ = running.join();
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
 }
 
-pub struct InputPin(cdev::LineHandle);
+impl Drop for OutputPin {
+    fn drop(&mut self) {
+        // Cut a running sequence short, rather than hold up whatever is
+        // releasing the pin until it finishes, but let it set the line to the
+        // level it ends with.
+        if let Some((running, cancel)) = self.running.take() {
+            cancel.store(true, Ordering::Relaxed);
+            let _ = running.join();
+        }
+    }
+}
+
+/// How an input pin's line is held: either just for reading its level, or
+/// also for watching its edges.
+enum InputLine {
+    Level(cdev::LineHandle),
+    Edges(EdgeMonitor),
+}
+
+pub struct InputPin {
+    line: InputLine,
+    config: LineConfig,
+    /// The edges which are reported as events.
+    edges: Option<Edges>,
+    /// How long the line must stay at a level before it is believed.
+    debounce: Option<Duration>,
+    /// The number of edges dropped before those last read from `events`,
+    /// until it is reported.
+    dropped: Option<u64>,
+}
+
+/// How long a request for the next edge waits unless given a `timeout`.
+const DEFAULT_EDGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a signal is measured for unless given a `window`.
+const DEFAULT_MEASUREMENT_WINDOW: Duration = Duration::from_secs(1);
+
+impl InputPin {
+    fn edge_monitor(&self) -> Result<&EdgeMonitor, Error> {
+        match (&self.line, self.edges) {
+            (InputLine::Edges(monitor), Some(_)) => Ok(monitor),
+            _ => Err(Error::new(
+                ErrorKind::NoSuchResource,
+                "edge-events is not configured for this pin",
+            )),
+        }
+    }
+
+    fn edge_error(&self, failure: String) -> Error {
+        let message = format!("watching line {} of {}", self.config.line, self.config.chip);
+        Error::new(ErrorKind::BrokenLimb, message).with_source(failure)
+    }
+
+    /// The edges since the last read of the `events` resource.
+    fn get_events(&mut self) -> Result<json::Value, Error> {
+        let (edges, dropped) = self
+            .edge_monitor()?
+            .take()
+            .map_err(|failure| self.edge_error(failure))?;
+        self.dropped = Some(dropped);
+        Ok(edges.into_iter().map(Edge::to_json).collect())
+    }
+
+    /// The next edge, waiting up to the `timeout` query parameter for it.
+    fn get_next_event(&self, query: &Query) -> Result<json::Value, Error> {
+        let timeout = query.duration("timeout")?.unwrap_or(DEFAULT_EDGE_TIMEOUT);
+        let edge = self
+            .edge_monitor()?
+            .next(timeout)
+            .map_err(|failure| self.edge_error(failure))?;
+        match edge {
+            Some(edge) => Ok(edge.to_json()),
+            None => {
+                let message = format!(
+                    "waiting for an edge on line {} of {}",
+                    self.config.line, self.config.chip
+                );
+                Err(Error::new(ErrorKind::Timeout, message))
+            }
+        }
+    }
+
+    /// A summary of the signal over the `window` query parameter.
+    fn get_measurement(&self, query: &Query) -> Result<json::Value, Error> {
+        let window = query
+            .duration("window")?
+            .unwrap_or(DEFAULT_MEASUREMENT_WINDOW);
+        let mut measurement = self
+            .edge_monitor()?
+            .measure(window)
+            .map_err(|failure| self.edge_error(failure))?
+            .to_json();
+        measurement["window"] = json::Value::String(format_duration(window));
+        Ok(measurement)
+    }
+}
 
 impl Limb for InputPin {
-    fn from_json(config: &json::Value) -> Option<Self> {
-        open_line_handle(config, cdev::LineRequestFlags::INPUT).map(|h| InputPin(h))
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError> {
+        Self::from_json_named("", config)
+    }
+
+    fn from_json_named(name: &str, config: &json::Value) -> Result<Self, ConfigError> {
+        let edges = match &config["edge-events"] {
+            json::Value::Null => Some(None),
+            json::Value::String(s) => Edges::from_name(s).map(Some),
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("edge-events", "\"rising\", \"falling\" or \"both\""))?;
+        let debounce = match &config["debounce"] {
+            json::Value::Null => Some(None),
+            json::Value::String(s) => parse_duration(s).map(Some),
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("debounce", "a duration such as 10ms"))?;
+        let config = LineConfig::from_json(name, config)?;
+        // Debouncing needs both edges, whichever are reported.
+        let requested = if debounce.is_some() {
+            Some(Edges::Both)
+        } else {
+            edges
+        };
+        let line = match requested {
+            Some(requested) => {
+                let handle = config.request_events(cdev::LineRequestFlags::INPUT, requested)?;
+                let monitor = EdgeMonitor::new(handle, edges, debounce).map_err(|e| {
+                    ConfigError::new("line", "a line which can be read").with_cdev_source(e)
+                })?;
+                InputLine::Edges(monitor)
+            }
+            None => InputLine::Level(config.request(cdev::LineRequestFlags::INPUT, 0)?),
+        };
+        Ok(InputPin {
+            line,
+            config,
+            edges,
+            debounce,
+            dropped: None,
+        })
     }
 
     fn set(&mut self, _value: String) -> Result<(), Error> {
-        Err(Error::InvalidOperation)
+        Err(Error::new(
+            ErrorKind::InvalidOperation,
+            "input pins cannot be set",
+        ))
     }
 
     fn get(&mut self) -> Result<String, Error> {
-        let value = self.0.get_value()?;
+        let value = match &self.line {
+            InputLine::Level(handle) => handle.get_value(),
+            InputLine::Edges(monitor) => monitor.value(),
+        }
+        .map_err(|e| self.config.line_error(ErrorKind::ReadFailed, "reading", e))?;
         match value {
-            1 => Ok(String::from("High")),
-            0 => Ok(String::from("Low")),
-            _ => Err(Error::BrokenLimb),
+            0 | 1 => Ok(level_name(value).to_owned()),
+            v => Err(Error::new(
+                ErrorKind::BrokenLimb,
+                format!("read unexpected value {}", v),
+            )),
+        }
+    }
+
+    fn get_resource(&mut self, resource: &str, query: &Query) -> Result<json::Value, Error> {
+        match resource {
+            "" => self.get().map(json::Value::String),
+            "events" => self.get_events(),
+            "events/next" => self.get_next_event(query),
+            "measure" => self.get_measurement(query),
+            _ => Err(no_such_resource(resource)),
+        }
+    }
+
+    fn annotations(&mut self) -> json::Map<String, json::Value> {
+        let mut annotations = json::Map::new();
+        if let Some(dropped) = self.dropped.take() {
+            annotations.insert("dropped".to_owned(), dropped.into());
         }
+        annotations
     }
 
     fn type_name(&self) -> &'static str {
         "input-pin"
     }
+
+    fn config_defaults() -> json::Value {
+        LineConfig::defaults()
+    }
+
+    fn to_json(&self) -> json::Value {
+        let mut config = self.config.to_json();
+        if let Some(edges) = self.edges {
+            config["edge-events"] = json::Value::String(edges.name().to_owned());
+        }
+        if let Some(debounce) = self.debounce {
+            config["debounce"] = json::Value::String(format_duration(debounce));
+        }
+        config
+    }
 }
 
-fn open_line_handle(
-    config: &json::Value,
-    flags: cdev::LineRequestFlags,
-) -> Option<cdev::LineHandle> {
-    let mut chip = match &config["chip"] {
-        json::Value::String(s) => cdev::Chip::new(s).ok(),
-        _ => None,
-    }?;
-    let line = match &config["line"] {
-        json::Value::Number(n) => n.as_u64().and_then(|x| x.try_into().ok()),
-        _ => None,
-    }?;
-    let type_flag = match &config["pin-type"] {
-        json::Value::String(s) => match s.as_ref() {
-            "push-pull" => Some(cdev::LineRequestFlags::empty()),
-            "open-drain" => Some(cdev::LineRequestFlags::OPEN_DRAIN),
+/// The most lines the kernel will request as one handle.
+const MAX_BUS_WIDTH: usize = 64;
+
+/// Several lines of one chip, requested as a single handle so that they are
+/// read and written together, as the bits of an integer.
+pub struct GpioBus {
+    handle: cdev::MultiLineHandle,
+    chip: String,
+    lines: Vec<u32>,
+    output: bool,
+    /// Whether the first line is the most significant bit.
+    msb_first: bool,
+    options: LineOptions,
+    initial: Option<u64>,
+}
+
+/// Parses an integer written in decimal, in hex with a `0x` prefix or in
+/// binary with a `0b` prefix.
+fn parse_integer(s: &str) -> Option<u64> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        parse_digits(hex, 16)
+    } else if let Some(binary) = s.strip_prefix("0b").or_else(|| s.strip_prefix("0B")) {
+        parse_digits(binary, 2)
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Parses digits after a prefix, which `from_str_radix` would otherwise
+/// accept with a sign in front.
+fn parse_digits(digits: &str, radix: u32) -> Option<u64> {
+    if digits.starts_with('+') {
+        return None;
+    }
+    u64::from_str_radix(digits, radix).ok()
+}
+
+/// The bit of a bus value which the `index`th of `width` lines carries.
+fn bus_bit(index: usize, width: usize, msb_first: bool) -> usize {
+    if msb_first {
+        width - 1 - index
+    } else {
+        index
+    }
+}
+
+/// The level of each of `width` lines which together carry `value`.
+fn bus_levels(value: u64, width: usize, msb_first: bool) -> Vec<u8> {
+    (0..width)
+        .map(|i| ((value >> bus_bit(i, width, msb_first)) & 1) as u8)
+        .collect()
+}
+
+/// The value carried by lines at `levels`.
+fn bus_value(levels: &[u8], msb_first: bool) -> u64 {
+    levels
+        .iter()
+        .enumerate()
+        .map(|(i, &level)| u64::from(level != 0) << bus_bit(i, levels.len(), msb_first))
+        .sum()
+}
+
+/// Parses a value for a bus of `width` lines, which it must fit in.
+fn parse_bus_value(value: &str, width: usize) -> Result<u64, String> {
+    let value = parse_integer(value)
+        .ok_or_else(|| "expected an integer in decimal, hex (0x) or binary (0b)".to_owned())?;
+    if width < 64 && value >> width != 0 {
+        return Err(format!("{} does not fit in {} lines", value, width));
+    }
+    Ok(value)
+}
+
+impl GpioBus {
+    fn width(&self) -> usize {
+        self.lines.len()
+    }
+
+    fn levels_of(&self, value: u64) -> Vec<u8> {
+        bus_levels(value, self.width(), self.msb_first)
+    }
+
+    fn parse_value(&self, value: &str) -> Result<u64, Error> {
+        parse_bus_value(value, self.width())
+            .map_err(|message| Error::new(ErrorKind::InvalidValue, message))
+    }
+
+    /// Formats a value in the format named by the `format` query parameter,
+    /// padding hex and binary to the width of the bus.
+    fn format_value(&self, value: u64, query: &Query) -> Result<String, Error> {
+        match query.get("format").unwrap_or("decimal") {
+            "decimal" => Ok(value.to_string()),
+            "hex" => Ok(format!(
+                "0x{:0width$x}",
+                value,
+                width = self.width().div_ceil(4)
+            )),
+            "binary" => Ok(format!("0b{:0width$b}", value, width = self.width())),
+            _ => Err(Error::new(
+                ErrorKind::InvalidValue,
+                "expected decimal, hex or binary for format",
+            )),
+        }
+    }
+
+    /// Reads every line at once. Output lines read back the levels they
+    /// are driven to.
+    fn read(&self) -> Result<u64, Error> {
+        let levels = self
+            .handle
+            .get_values()
+            .map_err(|e| self.bus_error(ErrorKind::ReadFailed, "reading", e))?;
+        Ok(bus_value(&levels, self.msb_first))
+    }
+
+    fn bus_error(&self, kind: ErrorKind, action: &str, error: cdev::errors::Error) -> Error {
+        let message = format!("{} lines {:?} of {}", action, self.lines, self.chip);
+        Error::new(kind, message).with_source(io_error_from_cdev(error))
+    }
+}
+
+impl Limb for GpioBus {
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError> {
+        Self::from_json_named("", config)
+    }
+
+    fn from_json_named(name: &str, config: &json::Value) -> Result<Self, ConfigError> {
+        let chip = chip_from_json(config)?;
+        let lines: Vec<u32> = match &config["lines"] {
+            json::Value::Array(lines) if (1..=MAX_BUS_WIDTH).contains(&lines.len()) => lines
+                .iter()
+                .map(|line| line.as_u64().and_then(|x| x.try_into().ok()))
+                .collect(),
             _ => None,
-        },
-        _ => None,
-    }?;
-    chip.get_line(line)
-        .ok()?
-        .request(flags | type_flag, 0, "Phal server")
-        .ok()
+        }
+        .ok_or_else(|| ConfigError::new("lines", "an array of 1 to 64 line offsets"))?;
+        let output = match &config["direction"] {
+            json::Value::String(s) => match s.as_ref() {
+                "input" => Some(false),
+                "output" => Some(true),
+                _ => None,
+            },
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("direction", "\"input\" or \"output\""))?;
+        let msb_first = match &config["bit-order"] {
+            json::Value::Null => Some(false),
+            json::Value::String(s) => match s.as_ref() {
+                "lsb-first" => Some(false),
+                "msb-first" => Some(true),
+                _ => None,
+            },
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("bit-order", "\"lsb-first\" or \"msb-first\""))?;
+        let initial = match &config["initial"] {
+            json::Value::Null => Ok(None),
+            json::Value::Number(n) => parse_bus_value(&n.to_string(), lines.len()).map(Some),
+            json::Value::String(s) => parse_bus_value(s, lines.len()).map(Some),
+            _ => Err("expected an integer".to_owned()),
+        }
+        .map_err(|e| ConfigError::new("initial", &format!("a value for the bus ({})", e)))?;
+        let options = LineOptions::from_json(name, config)?;
+        let direction = if output {
+            cdev::LineRequestFlags::OUTPUT
+        } else {
+            cdev::LineRequestFlags::INPUT
+        };
+        let levels = bus_levels(initial.unwrap_or(0), lines.len(), msb_first);
+        let handle = open_chip(&chip)?
+            .get_lines(&lines)
+            .map_err(|e| ConfigError::new("lines", "line offsets on the chip").with_cdev_source(e))?
+            .request(direction | options.flags(), &levels, &options.label)
+            .map_err(|e| {
+                ConfigError::new("lines", "lines which can be requested").with_cdev_source(e)
+            })?;
+        Ok(GpioBus {
+            handle,
+            chip,
+            lines,
+            output,
+            msb_first,
+            options,
+            initial,
+        })
+    }
+
+    fn set(&mut self, value: String) -> Result<(), Error> {
+        if !self.output {
+            return Err(Error::new(
+                ErrorKind::InvalidOperation,
+                "input buses cannot be set",
+            ));
+        }
+        let levels = self.levels_of(self.parse_value(&value)?);
+        self.handle
+            .set_values(&levels)
+            .map_err(|e| self.bus_error(ErrorKind::WriteFailed, "setting", e))
+    }
+
+    fn get(&mut self) -> Result<String, Error> {
+        self.read().map(|value| value.to_string())
+    }
+
+    fn get_resource(&mut self, resource: &str, query: &Query) -> Result<json::Value, Error> {
+        match resource {
+            "" => {
+                let value = self.format_value(self.read()?, query)?;
+                Ok(json::Value::String(value))
+            }
+            _ => Err(no_such_resource(resource)),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        "gpio-bus"
+    }
+
+    fn config_defaults() -> json::Value {
+        let mut defaults = LineConfig::defaults();
+        defaults["bit-order"] = json::Value::String("lsb-first".to_owned());
+        defaults
+    }
+
+    fn to_json(&self) -> json::Value {
+        let direction = if self.output { "output" } else { "input" };
+        let bit_order = if self.msb_first {
+            "msb-first"
+        } else {
+            "lsb-first"
+        };
+        let mut config = json::json!({
+            "chip": self.chip,
+            "lines": self.lines,
+            "direction": direction,
+            "bit-order": bit_order,
+        });
+        self.options.add_to_json(&mut config);
+        if let Some(initial) = self.initial {
+            config["initial"] = json::json!(initial);
+        }
+        config
+    }
+}
+
+/// How a line is driven when it is an output.
+#[derive(Clone, Copy, PartialEq)]
+enum Drive {
+    PushPull,
+    OpenDrain,
+    OpenSource,
+}
+
+/// The internal resistor a line is biased with.
+#[derive(Clone, Copy, PartialEq)]
+enum Bias {
+    PullUp,
+    PullDown,
+    Disabled,
+}
+
+// The bias flags of the kernel's line request (Linux 5.5 onwards), which
+// gpio-cdev doesn't name.
+const BIAS_PULL_UP: u32 = 1 << 5;
+const BIAS_PULL_DOWN: u32 = 1 << 6;
+const BIAS_DISABLE: u32 = 1 << 7;
+
+/// The options shared by every line a limb requests.
+#[derive(Clone)]
+struct LineOptions {
+    drive: Drive,
+    active_low: bool,
+    bias: Option<Bias>,
+    /// The consumer label given in the configuration, if any.
+    consumer: Option<String>,
+    /// The consumer label the lines are requested with, which is the limb's
+    /// name unless one is given.
+    label: String,
+}
+
+impl LineOptions {
+    fn from_json(name: &str, config: &json::Value) -> Result<Self, ConfigError> {
+        let drive = match &config["pin-type"] {
+            json::Value::String(s) => match s.as_ref() {
+                "push-pull" => Some(Drive::PushPull),
+                "open-drain" => Some(Drive::OpenDrain),
+                "open-source" => Some(Drive::OpenSource),
+                _ => None,
+            },
+            _ => None,
+        }
+        .ok_or_else(|| {
+            ConfigError::new(
+                "pin-type",
+                "\"push-pull\", \"open-drain\" or \"open-source\"",
+            )
+        })?;
+        let active_low = match &config["active-low"] {
+            json::Value::Null => Ok(false),
+            json::Value::Bool(b) => Ok(*b),
+            _ => Err(ConfigError::new("active-low", "true or false")),
+        }?;
+        let bias = match &config["bias"] {
+            json::Value::Null => Some(None),
+            json::Value::String(s) => match s.as_ref() {
+                "pull-up" => Some(Some(Bias::PullUp)),
+                "pull-down" => Some(Some(Bias::PullDown)),
+                "disabled" => Some(Some(Bias::Disabled)),
+                _ => None,
+            },
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("bias", "\"pull-up\", \"pull-down\" or \"disabled\""))?;
+        let consumer = match &config["consumer"] {
+            json::Value::Null => Ok(None),
+            json::Value::String(s) => Ok(Some(s.clone())),
+            _ => Err(ConfigError::new("consumer", "a label for the line")),
+        }?;
+        let label = match &consumer {
+            Some(consumer) => consumer.clone(),
+            None if name.is_empty() => "Phal server".to_owned(),
+            None => name.to_owned(),
+        };
+        Ok(LineOptions {
+            drive,
+            active_low,
+            bias,
+            consumer,
+            label,
+        })
+    }
+
+    /// Adds the options to a limb's configuration.
+    fn add_to_json(&self, config: &mut json::Value) {
+        let pin_type = match self.drive {
+            Drive::PushPull => "push-pull",
+            Drive::OpenDrain => "open-drain",
+            Drive::OpenSource => "open-source",
+        };
+        config["pin-type"] = json::Value::String(pin_type.to_owned());
+        if self.active_low {
+            config["active-low"] = json::Value::Bool(true);
+        }
+        if let Some(bias) = self.bias {
+            let bias = match bias {
+                Bias::PullUp => "pull-up",
+                Bias::PullDown => "pull-down",
+                Bias::Disabled => "disabled",
+            };
+            config["bias"] = json::Value::String(bias.to_owned());
+        }
+        if let Some(consumer) = &self.consumer {
+            config["consumer"] = json::Value::String(consumer.clone());
+        }
+    }
+
+    /// The request flags for the configured drive, polarity and bias.
+    fn flags(&self) -> cdev::LineRequestFlags {
+        let mut flags = match self.drive {
+            Drive::PushPull => cdev::LineRequestFlags::empty(),
+            Drive::OpenDrain => cdev::LineRequestFlags::OPEN_DRAIN,
+            Drive::OpenSource => cdev::LineRequestFlags::OPEN_SOURCE,
+        };
+        if self.active_low {
+            flags |= cdev::LineRequestFlags::ACTIVE_LOW;
+        }
+        let bias = match self.bias {
+            Some(Bias::PullUp) => BIAS_PULL_UP,
+            Some(Bias::PullDown) => BIAS_PULL_DOWN,
+            Some(Bias::Disabled) => BIAS_DISABLE,
+            None => 0,
+        };
+        // Safe, as the flags are only passed on to the kernel, which
+        // rejects any it doesn't support.
+        flags | unsafe { cdev::LineRequestFlags::from_bits_unchecked(bias) }
+    }
+}
+
+fn chip_from_json(config: &json::Value) -> Result<String, ConfigError> {
+    match &config["chip"] {
+        json::Value::String(s) => Ok(s.clone()),
+        _ => Err(ConfigError::new("chip", "the path of a GPIO chip")),
+    }
+}
+
+fn open_chip(path: &str) -> Result<cdev::Chip, ConfigError> {
+    cdev::Chip::new(path)
+        .map_err(|e| ConfigError::new("chip", "the path of a GPIO chip").with_cdev_source(e))
+}
+
+/// The configuration shared by input and output pins.
+#[derive(Clone)]
+pub(crate) struct LineConfig {
+    chip: String,
+    line: u32,
+    options: LineOptions,
+}
+
+impl LineConfig {
+    pub(crate) fn from_json(name: &str, config: &json::Value) -> Result<Self, ConfigError> {
+        let chip = chip_from_json(config)?;
+        let line = match &config["line"] {
+            json::Value::Number(n) => n.as_u64().and_then(|x| x.try_into().ok()),
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("line", "a line offset"))?;
+        let options = LineOptions::from_json(name, config)?;
+        Ok(LineConfig {
+            chip,
+            line,
+            options,
+        })
+    }
+
+    /// The defaults of the options which are left out of a configuration
+    /// unless they differ from them.
+    pub(crate) fn defaults() -> json::Value {
+        json::json!({ "active-low": false })
+    }
+
+    pub(crate) fn to_json(&self) -> json::Value {
+        let mut config = json::json!({
+            "chip": self.chip,
+            "line": self.line,
+        });
+        self.options.add_to_json(&mut config);
+        config
+    }
+
+    /// An error from gpio-cdev during an operation on the line, described
+    /// as e.g. "setting line 13 of /dev/gpiochip0".
+    pub(crate) fn line_error(
+        &self,
+        kind: ErrorKind,
+        action: &str,
+        error: cdev::errors::Error,
+    ) -> Error {
+        let message = format!("{} line {} of {}", action, self.line, self.chip);
+        Error::new(kind, message).with_source(io_error_from_cdev(error))
+    }
+
+    fn get_line(&self) -> Result<cdev::Line, ConfigError> {
+        open_chip(&self.chip)?
+            .get_line(self.line)
+            .map_err(|e| ConfigError::new("line", "a line offset on the chip").with_cdev_source(e))
+    }
+
+    /// Requests the line, driving it to `initial` straight away if it is an
+    /// output.
+    pub(crate) fn request(
+        &self,
+        flags: cdev::LineRequestFlags,
+        initial: u8,
+    ) -> Result<cdev::LineHandle, ConfigError> {
+        self.get_line()?
+            .request(flags | self.options.flags(), initial, &self.options.label)
+            .map_err(|e| {
+                ConfigError::new("line", "a line which can be requested").with_cdev_source(e)
+            })
+    }
+
+    fn request_events(
+        &self,
+        flags: cdev::LineRequestFlags,
+        edges: Edges,
+    ) -> Result<cdev::LineEventHandle, ConfigError> {
+        self.get_line()?
+            .events(
+                flags | self.options.flags(),
+                edges.flags(),
+                &self.options.label,
+            )
+            .map_err(|e| {
+                ConfigError::new("edge-events", "a line which supports edge events")
+                    .with_cdev_source(e)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bus_values_are_spread_over_lines_in_bit_order() {
+        assert_eq!(bus_levels(0b0011, 4, false), vec![1, 1, 0, 0]);
+        assert_eq!(bus_levels(0b0011, 4, true), vec![0, 0, 1, 1]);
+        assert_eq!(bus_levels(1 << 63, 64, true)[0], 1);
+        assert_eq!(bus_levels(1 << 63, 64, false)[63], 1);
+    }
+
+    #[test]
+    fn bus_values_are_read_back_in_bit_order() {
+        assert_eq!(bus_value(&[1, 0, 0], false), 1);
+        assert_eq!(bus_value(&[1, 0, 0], true), 4);
+        for &msb_first in &[false, true] {
+            for &(value, width) in &[(0xa5, 8), (0x5, 3), (u64::MAX, 64)] {
+                let levels = bus_levels(value, width, msb_first);
+                assert_eq!(bus_value(&levels, msb_first), value);
+            }
+        }
+    }
+
+    #[test]
+    fn bus_values_are_parsed_in_decimal_hex_or_binary() {
+        assert_eq!(parse_bus_value("10", 8), Ok(10));
+        assert_eq!(parse_bus_value(" 0x1F ", 8), Ok(0x1f));
+        assert_eq!(parse_bus_value("0XfF", 8), Ok(0xff));
+        assert_eq!(parse_bus_value("0b101", 3), Ok(5));
+        assert_eq!(parse_bus_value("0xffffffffffffffff", 64), Ok(u64::MAX));
+        for invalid in &["", "0x", "0b", "0b102", "0xg", "0x+1", "-1", "1.5", "ten"] {
+            assert!(parse_bus_value(invalid, 8).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn values_wider_than_the_bus_are_rejected() {
+        assert_eq!(parse_bus_value("0b111", 3), Ok(7));
+        assert!(parse_bus_value("8", 3).is_err());
+        assert!(parse_bus_value("0x100", 8).is_err());
+        assert!(parse_bus_value("0x10000000000000000", 64).is_err());
+    }
+
+    fn line_options(config: json::Value) -> Result<LineOptions, ConfigError> {
+        LineOptions::from_json("gpio_10", &config)
+    }
+
+    #[test]
+    fn line_options_are_parsed_from_a_configuration() {
+        let options = line_options(json::json!({"pin-type": "push-pull"})).unwrap();
+        assert!(options.drive == Drive::PushPull);
+        assert!(!options.active_low);
+        assert!(options.bias.is_none());
+        assert_eq!(options.consumer, None);
+        assert_eq!(options.label, "gpio_10");
+
+        let options = line_options(json::json!({
+            "pin-type": "open-drain",
+            "active-low": true,
+            "bias": "pull-down",
+            "consumer": "fan",
+        }))
+        .unwrap();
+        assert!(options.drive == Drive::OpenDrain);
+        assert!(options.active_low);
+        assert!(options.bias == Some(Bias::PullDown));
+        assert_eq!(options.consumer.as_deref(), Some("fan"));
+        assert_eq!(options.label, "fan");
+
+        let options = LineOptions::from_json("", &json::json!({"pin-type": "open-source"}));
+        assert_eq!(options.unwrap().label, "Phal server");
+    }
+
+    #[test]
+    fn invalid_line_options_are_rejected() {
+        assert_eq!(
+            line_options(json::json!({})).err().unwrap().path(),
+            "pin-type"
+        );
+        let invalid = [
+            ("pin-type", json::json!("open-collector")),
+            ("pin-type", json::json!(1)),
+            ("active-low", json::json!("yes")),
+            ("bias", json::json!("pull-sideways")),
+            ("bias", json::json!(true)),
+            ("consumer", json::json!(7)),
+        ];
+        for (field, value) in &invalid {
+            let mut config = json::json!({"pin-type": "push-pull"});
+            config[field] = value.clone();
+            let error = line_options(config).err().unwrap();
+            assert_eq!(error.path(), *field, "{}", value);
+        }
+    }
+
+    #[test]
+    fn line_options_give_the_kernel_request_flags() {
+        let flags = |config: json::Value| line_options(config).unwrap().flags().bits();
+        assert_eq!(flags(json::json!({"pin-type": "push-pull"})), 0);
+        assert_eq!(flags(json::json!({"pin-type": "open-drain"})), 1 << 3);
+        assert_eq!(flags(json::json!({"pin-type": "open-source"})), 1 << 4);
+        assert_eq!(
+            flags(json::json!({"pin-type": "push-pull", "active-low": true})),
+            1 << 2
+        );
+        assert_eq!(
+            flags(json::json!({"pin-type": "push-pull", "bias": "pull-up"})),
+            1 << 5
+        );
+        assert_eq!(
+            flags(json::json!({"pin-type": "push-pull", "bias": "pull-down"})),
+            1 << 6
+        );
+        assert_eq!(
+            flags(json::json!({"pin-type": "push-pull", "bias": "disabled"})),
+            1 << 7
+        );
+        assert_eq!(
+            flags(json::json!({
+                "pin-type": "open-drain",
+                "active-low": true,
+                "bias": "pull-up",
+            })),
+            (1 << 3) | (1 << 2) | (1 << 5)
+        );
+    }
 }
@@ -0,0 +1,172 @@
+// Copyright (C) 2020 Arron Speake
+
+use crate::{duration::parse_duration, pin::parse_level};
+use gpio_cdev as cdev;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often a wait checks whether its sequence has been cancelled.
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// One step of a sequence of commands for an output pin.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    Set(u8),
+    Toggle,
+    Wait(Duration),
+}
+
+/// Parses a sequence of commands separated by whitespace, where each is
+/// one of:
+///
+/// - `High` or `Low`, which sets the pin.
+/// - `Toggle`, which sets the pin to the opposite of its current level.
+/// - A duration such as `10ms`, which waits before the next command.
+/// - `Pulse <level> <duration>`, which sets the pin to the level, waits,
+///   and then sets it to the opposite level.
+pub fn parse_sequence(commands: &str) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+    let mut words = commands.split_whitespace();
+    while let Some(word) = words.next() {
+        if word == "Toggle" {
+            steps.push(Step::Toggle);
+        } else if word == "Pulse" {
+            let level = words.next().and_then(parse_level);
+            let duration = words.next().and_then(parse_duration);
+            match (level, duration) {
+                (Some(level), Some(duration)) => {
+                    steps.extend(&[Step::Set(level), Step::Wait(duration), Step::Set(1 - level)])
+                }
+                _ => {
+                    return Err(
+                        "expected Pulse High or Low and a duration, e.g. Pulse High 200ms"
+                            .to_owned(),
+                    )
+                }
+            }
+        } else if let Some(level) = parse_level(word) {
+            steps.push(Step::Set(level));
+        } else if let Some(duration) = parse_duration(word) {
+            steps.push(Step::Wait(duration));
+        } else {
+            return Err(format!(
+                "expected High, Low, Toggle, Pulse or a duration, found {}",
+                word
+            ));
+        }
+    }
+    if steps.is_empty() {
+        Err("expected High or Low".to_owned())
+    } else {
+        Ok(steps)
+    }
+}
+
+/// Runs a sequence on a line, stopping at the first step which fails. A
+/// failure is given with what was being done, "setting" or "reading".
+///
+/// Once `cancel` is set, the remaining waits are skipped, so that the line
+/// is still left at the level the sequence ends with, e.g. a pulse still
+/// ends, just early.
+pub fn run_sequence(
+    handle: &cdev::LineHandle,
+    steps: &[Step],
+    cancel: &AtomicBool,
+) -> Result<(), (&'static str, cdev::errors::Error)> {
+    for step in steps {
+        match *step {
+            Step::Set(level) => handle.set_value(level).map_err(|e| ("setting", e))?,
+            Step::Toggle => {
+                let level = handle.get_value().map_err(|e| ("reading", e))?;
+                handle
+                    .set_value(1 - level.min(1))
+                    .map_err(|e| ("setting", e))?;
+            }
+            Step::Wait(duration) => wait(duration, cancel),
+        }
+    }
+    Ok(())
+}
+
+/// Sleeps for `duration`, or until `cancel` is set.
+fn wait(duration: Duration, cancel: &AtomicBool) {
+    let start = Instant::now();
+    while !cancel.load(Ordering::Relaxed) {
+        let remaining = duration.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            break;
+        }
+        thread::sleep(remaining.min(CANCEL_CHECK_INTERVAL));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn levels_toggles_and_waits_are_parsed() {
+        assert_eq!(
+            parse_sequence("High 10ms Toggle 1.5s Low"),
+            Ok(vec![
+                Step::Set(1),
+                Step::Wait(ms(10)),
+                Step::Toggle,
+                Step::Wait(ms(1500)),
+                Step::Set(0),
+            ])
+        );
+    }
+
+    #[test]
+    fn pulses_return_to_the_opposite_level() {
+        assert_eq!(
+            parse_sequence("Pulse High 200ms"),
+            Ok(vec![Step::Set(1), Step::Wait(ms(200)), Step::Set(0)])
+        );
+        assert_eq!(
+            parse_sequence("  Pulse Low 50us\n"),
+            Ok(vec![
+                Step::Set(0),
+                Step::Wait(Duration::from_micros(50)),
+                Step::Set(1),
+            ])
+        );
+    }
+
+    #[test]
+    fn incomplete_pulses_are_rejected() {
+        assert!(parse_sequence("Pulse").is_err());
+        assert!(parse_sequence("Pulse High").is_err());
+        assert!(parse_sequence("Pulse 10ms High").is_err());
+        assert!(parse_sequence("Pulse Toggle 10ms").is_err());
+    }
+
+    #[test]
+    fn unknown_commands_are_rejected() {
+        let error = parse_sequence("High Sideways").unwrap_err();
+        assert!(error.contains("Sideways"));
+        assert!(parse_sequence("high").is_err());
+        assert!(parse_sequence("10parsecs").is_err());
+    }
+
+    #[test]
+    fn empty_sequences_are_rejected() {
+        assert!(parse_sequence("").is_err());
+        assert!(parse_sequence(" \t ").is_err());
+    }
+
+    #[test]
+    fn cancelled_waits_end_early() {
+        let start = Instant::now();
+        wait(Duration::from_secs(10), &AtomicBool::new(true));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
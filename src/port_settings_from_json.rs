@@ -5,38 +5,43 @@
  * Copyright (C) 2020 Callum David O'Brien
  */
 
+use crate::config_error::ConfigError;
 use serde_json as json;
 use std::convert::TryInto;
 
 /// Shared JSON settings import for XModem and Serial
-pub fn port_settings_from_json(config: &json::Value) -> Option<serial::PortSettings> {
+pub fn port_settings_from_json(config: &json::Value) -> Result<serial::PortSettings, ConfigError> {
     let baud_rate = match &config["baud-rate"] {
-        json::Value::Number(n) => Some(match n.as_u64()? {
-            110 => serial::BaudRate::Baud110,
-            300 => serial::BaudRate::Baud300,
-            600 => serial::BaudRate::Baud600,
-            1200 => serial::BaudRate::Baud1200,
-            2400 => serial::BaudRate::Baud2400,
-            4800 => serial::BaudRate::Baud4800,
-            9600 => serial::BaudRate::Baud9600,
-            19200 => serial::BaudRate::Baud19200,
-            38400 => serial::BaudRate::Baud38400,
-            57600 => serial::BaudRate::Baud57600,
-            115200 => serial::BaudRate::Baud115200,
-            m => serial::BaudRate::BaudOther(m.try_into().ok()?),
+        json::Value::Number(n) => n.as_u64().and_then(|n| {
+            Some(match n {
+                110 => serial::BaudRate::Baud110,
+                300 => serial::BaudRate::Baud300,
+                600 => serial::BaudRate::Baud600,
+                1200 => serial::BaudRate::Baud1200,
+                2400 => serial::BaudRate::Baud2400,
+                4800 => serial::BaudRate::Baud4800,
+                9600 => serial::BaudRate::Baud9600,
+                19200 => serial::BaudRate::Baud19200,
+                38400 => serial::BaudRate::Baud38400,
+                57600 => serial::BaudRate::Baud57600,
+                115200 => serial::BaudRate::Baud115200,
+                m => serial::BaudRate::BaudOther(m.try_into().ok()?),
+            })
         }),
         _ => None,
-    }?;
+    }
+    .ok_or_else(|| ConfigError::new("baud-rate", "a baud rate"))?;
     let char_size = match &config["char-size"] {
-        json::Value::Number(n) => match n.as_u64()? {
-            5 => Some(serial::CharSize::Bits5),
-            6 => Some(serial::CharSize::Bits6),
-            7 => Some(serial::CharSize::Bits7),
-            8 => Some(serial::CharSize::Bits8),
+        json::Value::Number(n) => match n.as_u64() {
+            Some(5) => Some(serial::CharSize::Bits5),
+            Some(6) => Some(serial::CharSize::Bits6),
+            Some(7) => Some(serial::CharSize::Bits7),
+            Some(8) => Some(serial::CharSize::Bits8),
             _ => None,
         },
         _ => None,
-    }?;
+    }
+    .ok_or_else(|| ConfigError::new("char-size", "5, 6, 7 or 8"))?;
     let parity = match &config["parity"] {
         json::Value::String(s) => match s.as_ref() {
             "none" => Some(serial::Parity::ParityNone),
@@ -45,15 +50,17 @@ pub fn port_settings_from_json(config: &json::Value) -> Option<serial::PortSetti
             _ => None,
         },
         _ => None,
-    }?;
+    }
+    .ok_or_else(|| ConfigError::new("parity", "\"none\", \"odd\" or \"even\""))?;
     let stop_bits = match &config["stop-bits"] {
-        json::Value::Number(n) => match n.as_u64()? {
-            1 => Some(serial::StopBits::Stop1),
-            2 => Some(serial::StopBits::Stop2),
+        json::Value::Number(n) => match n.as_u64() {
+            Some(1) => Some(serial::StopBits::Stop1),
+            Some(2) => Some(serial::StopBits::Stop2),
             _ => None,
         },
         _ => None,
-    }?;
+    }
+    .ok_or_else(|| ConfigError::new("stop-bits", "1 or 2"))?;
     let flow_control = match &config["flow-control"] {
         json::Value::String(s) => match s.as_ref() {
             "none" => Some(serial::FlowControl::FlowNone),
@@ -62,8 +69,9 @@ pub fn port_settings_from_json(config: &json::Value) -> Option<serial::PortSetti
             _ => None,
         },
         _ => None,
-    }?;
-    Some(serial::PortSettings {
+    }
+    .ok_or_else(|| ConfigError::new("flow-control", "\"none\", \"software\" or \"hardware\""))?;
+    Ok(serial::PortSettings {
         baud_rate,
         char_size,
         parity,
@@ -71,3 +79,35 @@ pub fn port_settings_from_json(config: &json::Value) -> Option<serial::PortSetti
         flow_control,
     })
 }
+
+/// Shared JSON settings export for XModem and Serial, the inverse of
+/// `port_settings_from_json`.
+pub fn port_settings_to_json(settings: &serial::PortSettings) -> json::Value {
+    let char_size = match settings.char_size {
+        serial::CharSize::Bits5 => 5,
+        serial::CharSize::Bits6 => 6,
+        serial::CharSize::Bits7 => 7,
+        serial::CharSize::Bits8 => 8,
+    };
+    let parity = match settings.parity {
+        serial::Parity::ParityNone => "none",
+        serial::Parity::ParityOdd => "odd",
+        serial::Parity::ParityEven => "even",
+    };
+    let stop_bits = match settings.stop_bits {
+        serial::StopBits::Stop1 => 1,
+        serial::StopBits::Stop2 => 2,
+    };
+    let flow_control = match settings.flow_control {
+        serial::FlowControl::FlowNone => "none",
+        serial::FlowControl::FlowSoftware => "software",
+        serial::FlowControl::FlowHardware => "hardware",
+    };
+    json::json!({
+        "baud-rate": settings.baud_rate.speed(),
+        "char-size": char_size,
+        "parity": parity,
+        "stop-bits": stop_bits,
+        "flow-control": flow_control,
+    })
+}
@@ -0,0 +1,505 @@
+// Copyright (C) 2020 Arron Speake
+
+use crate::{
+    duration::{format_duration, parse_duration},
+    limb::{no_such_resource, ConfigError, Error, ErrorKind, Limb, Query},
+};
+use serde_json as json;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_SYSFS_ROOT: &str = "/sys/class/pwm";
+
+/// How long to wait for a channel's files to appear once it is exported.
+/// udev may still be setting their permissions after the kernel creates
+/// them.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The settings of a channel which can be read and written individually,
+/// as `/limb/<name>/<setting>`.
+const SETTINGS: [&str; 4] = ["period", "duty-cycle", "polarity", "enabled"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Polarity {
+    Normal,
+    Inversed,
+}
+
+impl Polarity {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "normal" => Some(Polarity::Normal),
+            "inversed" => Some(Polarity::Inversed),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Polarity::Normal => "normal",
+            Polarity::Inversed => "inversed",
+        }
+    }
+}
+
+/// A duty cycle, in the form shared by the `pwm` and `soft-pwm` limbs.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum DutyCycle {
+    Time(Duration),
+    /// A fraction of the period, from 0 to 1.
+    Fraction(f64),
+}
+
+pub(crate) const DUTY_CYCLE_EXPECTED: &str =
+    "a fraction of the period from 0 to 1, a percentage such as 25% or a duration such as 1500us";
+
+pub(crate) const ENABLED_EXPECTED: &str = "true, false, On, Off, 1 or 0";
+
+impl DutyCycle {
+    /// Parses a number as a fraction of the period, or a string as either a
+    /// percentage such as `25%` or a duration such as `1500us`.
+    pub(crate) fn from_json(value: &json::Value) -> Option<Self> {
+        let fraction = match value {
+            json::Value::Number(n) => n.as_f64()?,
+            json::Value::String(s) => match s.trim().strip_suffix('%') {
+                Some(percentage) => percentage.trim().parse::<f64>().ok()? / 100.0,
+                None => return parse_duration(s).map(DutyCycle::Time),
+            },
+            _ => return None,
+        };
+        if (0.0..=1.0).contains(&fraction) {
+            Some(DutyCycle::Fraction(fraction))
+        } else {
+            None
+        }
+    }
+
+    /// How long the signal is active for in each `period`.
+    pub(crate) fn time_in(self, period: Duration) -> Duration {
+        match self {
+            DutyCycle::Time(time) => time,
+            DutyCycle::Fraction(fraction) => period.mul_f64(fraction),
+        }
+    }
+
+    /// The fraction of `period` for which the signal is active, which is
+    /// more than 1 if the duty cycle is longer than the period.
+    pub(crate) fn fraction_of(self, period: Duration) -> f64 {
+        match self {
+            DutyCycle::Time(time) => time.as_secs_f64() / period.as_secs_f64(),
+            DutyCycle::Fraction(fraction) => fraction,
+        }
+    }
+
+    fn to_json(self) -> json::Value {
+        match self {
+            DutyCycle::Time(time) => json::Value::String(format_duration(time)),
+            DutyCycle::Fraction(fraction) => json::json!(fraction),
+        }
+    }
+}
+
+/// Parses whether a signal is enabled, given as a boolean or as one of the
+/// strings `true`, `false`, `On`, `Off`, `1` or `0`.
+pub(crate) fn enabled_from_json(value: &json::Value) -> Option<bool> {
+    match value {
+        json::Value::Bool(b) => Some(*b),
+        json::Value::String(s) => match s.trim() {
+            "On" | "true" | "1" => Some(true),
+            "Off" | "false" | "0" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Changes to the settings of a channel. Settings which are absent are
+/// left as they are.
+#[derive(Clone, Default, PartialEq)]
+struct Changes {
+    period: Option<Duration>,
+    duty_cycle: Option<DutyCycle>,
+    polarity: Option<Polarity>,
+    enabled: Option<bool>,
+}
+
+/// Parses a time given either as a number of nanoseconds or as a duration
+/// such as `20ms`.
+fn time_from_json(value: &json::Value) -> Option<Duration> {
+    match value {
+        json::Value::Number(n) => n.as_u64().map(Duration::from_nanos),
+        json::Value::String(s) => parse_duration(s),
+        _ => None,
+    }
+}
+
+impl Changes {
+    /// Parses the settings in a JSON object, returning the name of the first
+    /// which is invalid.
+    fn from_json(value: &json::Value) -> Result<Self, &'static str> {
+        let mut changes = Changes::default();
+        if !value["period"].is_null() {
+            changes.period = Some(time_from_json(&value["period"]).ok_or("period")?);
+        }
+        if !value["duty-cycle"].is_null() {
+            changes.duty_cycle =
+                Some(DutyCycle::from_json(&value["duty-cycle"]).ok_or("duty-cycle")?);
+        }
+        changes.polarity = match &value["polarity"] {
+            json::Value::Null => None,
+            json::Value::String(s) => Some(Polarity::from_name(s).ok_or("polarity")?),
+            _ => return Err("polarity"),
+        };
+        if !value["enabled"].is_null() {
+            changes.enabled = Some(enabled_from_json(&value["enabled"]).ok_or("enabled")?);
+        }
+        Ok(changes)
+    }
+
+    fn to_json(&self) -> json::Value {
+        let mut value = json::json!({});
+        if let Some(period) = self.period {
+            value["period"] = json::Value::String(format_duration(period));
+        }
+        if let Some(duty_cycle) = self.duty_cycle {
+            value["duty-cycle"] = duty_cycle.to_json();
+        }
+        if let Some(polarity) = self.polarity {
+            value["polarity"] = json::Value::String(polarity.name().to_owned());
+        }
+        if let Some(enabled) = self.enabled {
+            value["enabled"] = json::Value::Bool(enabled);
+        }
+        value
+    }
+
+    fn expected(setting: &str) -> &'static str {
+        match setting {
+            "period" => "a time in nanoseconds or a duration such as 20ms",
+            "duty-cycle" => DUTY_CYCLE_EXPECTED,
+            "polarity" => "\"normal\" or \"inversed\"",
+            "enabled" => ENABLED_EXPECTED,
+            _ => "a JSON object of settings, On or Off",
+        }
+    }
+}
+
+/// The settings of a channel, as read from sysfs.
+struct Settings {
+    period: Duration,
+    duty_cycle: Duration,
+    polarity: Polarity,
+    enabled: bool,
+}
+
+impl Settings {
+    fn to_json(&self) -> json::Value {
+        json::json!({
+            "period": format_duration(self.period),
+            "duty-cycle": format_duration(self.duty_cycle),
+            "polarity": self.polarity.name(),
+            "enabled": self.enabled,
+        })
+    }
+}
+
+/// A channel of a PWM chip, driven through the sysfs PWM class, i.e.
+/// `/sys/class/pwm/pwmchipN/pwmM`.
+pub struct Pwm {
+    sysfs_root: Option<String>,
+    chip: u32,
+    channel: u32,
+    /// The channel's directory.
+    path: PathBuf,
+    /// Whether the channel was exported by this limb, and so should be
+    /// unexported when it is released.
+    exported: bool,
+    initial: Changes,
+}
+
+impl Pwm {
+    fn file(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    fn read_file(&self, name: &str) -> Result<String, Error> {
+        let path = self.file(name);
+        fs::read_to_string(&path)
+            .map(|s| s.trim().to_owned())
+            .map_err(|e| {
+                Error::from_io(
+                    ErrorKind::ReadFailed,
+                    format!("reading {}", path.display()),
+                    e,
+                )
+            })
+    }
+
+    fn write_file(&self, name: &str, value: &str) -> Result<(), Error> {
+        let path = self.file(name);
+        fs::write(&path, value).map_err(|e| {
+            Error::from_io(
+                ErrorKind::WriteFailed,
+                format!("writing {}", path.display()),
+                e,
+            )
+        })
+    }
+
+    fn read_time(&self, name: &str) -> Result<Duration, Error> {
+        let value = self.read_file(name)?;
+        value.parse().map(Duration::from_nanos).map_err(|_| {
+            let message = format!("read unexpected {} {}", name, value);
+            Error::new(ErrorKind::BrokenLimb, message)
+        })
+    }
+
+    fn read(&self) -> Result<Settings, Error> {
+        let polarity = self.read_file("polarity")?;
+        let polarity = Polarity::from_name(&polarity).ok_or_else(|| {
+            let message = format!("read unexpected polarity {}", polarity);
+            Error::new(ErrorKind::BrokenLimb, message)
+        })?;
+        Ok(Settings {
+            period: self.read_time("period")?,
+            duty_cycle: self.read_time("duty_cycle")?,
+            polarity,
+            enabled: self.read_file("enable")? == "1",
+        })
+    }
+
+    /// Applies changes in an order the kernel accepts: the polarity can
+    /// only be changed while the channel is disabled, and the duty cycle can
+    /// never be longer than the period.
+    fn apply(&self, changes: &Changes) -> Result<(), Error> {
+        let current = self.read()?;
+        let period = changes.period.unwrap_or(current.period);
+        let duty_cycle = changes
+            .duty_cycle
+            .map_or(current.duty_cycle, |duty_cycle| duty_cycle.time_in(period));
+        if duty_cycle > period {
+            let message = format!(
+                "the duty cycle ({}) is longer than the period ({})",
+                format_duration(duty_cycle),
+                format_duration(period)
+            );
+            return Err(Error::new(ErrorKind::InvalidValue, message));
+        }
+        let polarity = changes.polarity.filter(|p| *p != current.polarity);
+        if let Some(polarity) = polarity {
+            if current.enabled {
+                self.write_file("enable", "0")?;
+            }
+            self.write_file("polarity", polarity.name())?;
+        }
+        let period_ns = period.as_nanos().to_string();
+        let duty_cycle_ns = duty_cycle.as_nanos().to_string();
+        if duty_cycle > current.period {
+            self.write_file("period", &period_ns)?;
+            self.write_file("duty_cycle", &duty_cycle_ns)?;
+        } else {
+            self.write_file("duty_cycle", &duty_cycle_ns)?;
+            self.write_file("period", &period_ns)?;
+        }
+        let enabled = changes.enabled.unwrap_or(current.enabled);
+        if enabled != current.enabled || (polarity.is_some() && enabled) {
+            self.write_file("enable", if enabled { "1" } else { "0" })?;
+        }
+        Ok(())
+    }
+
+    fn invalid_setting(setting: &str) -> Error {
+        let message = format!("expected {}", Changes::expected(setting));
+        Error::new(ErrorKind::InvalidValue, message)
+    }
+}
+
+/// Exports a channel, waiting until its files can be written.
+fn export(chip_path: &Path, channel: u32, path: &Path) -> io::Result<()> {
+    fs::write(chip_path.join("export"), channel.to_string())?;
+    let deadline = Instant::now() + EXPORT_TIMEOUT;
+    loop {
+        match fs::OpenOptions::new().write(true).open(path.join("enable")) {
+            Ok(_) => return Ok(()),
+            Err(error) if Instant::now() >= deadline => return Err(error),
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+fn index_from_json(value: &json::Value) -> Option<u32> {
+    value
+        .as_u64()
+        .and_then(|x| std::convert::TryInto::try_into(x).ok())
+}
+
+impl Limb for Pwm {
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError> {
+        let sysfs_root = match &config["sysfs-root"] {
+            json::Value::Null => Ok(None),
+            json::Value::String(s) => Ok(Some(s.clone())),
+            _ => Err(ConfigError::new(
+                "sysfs-root",
+                "the path of the sysfs PWM class",
+            )),
+        }?;
+        let chip = index_from_json(&config["chip"])
+            .ok_or_else(|| ConfigError::new("chip", "the number of a PWM chip"))?;
+        let channel = index_from_json(&config["channel"])
+            .ok_or_else(|| ConfigError::new("channel", "the number of a channel of the chip"))?;
+        let initial = Changes::from_json(config)
+            .map_err(|setting| ConfigError::new(setting, Changes::expected(setting)))?;
+
+        let root = Path::new(sysfs_root.as_deref().unwrap_or(DEFAULT_SYSFS_ROOT));
+        let chip_path = root.join(format!("pwmchip{}", chip));
+        fs::metadata(&chip_path)
+            .map_err(|e| ConfigError::new("chip", "the number of a PWM chip").with_source(e))?;
+        let path = chip_path.join(format!("pwm{}", channel));
+        let exported = !path.exists();
+        if exported {
+            export(&chip_path, channel, &path).map_err(|e| {
+                ConfigError::new("channel", "a channel which can be exported").with_source(e)
+            })?;
+        }
+        let pwm = Pwm {
+            sysfs_root,
+            chip,
+            channel,
+            path,
+            exported,
+            initial,
+        };
+        pwm.apply(&pwm.initial).map_err(|error| {
+            let expected = format!("settings the channel accepts ({})", error);
+            ConfigError::root(&expected)
+        })?;
+        Ok(pwm)
+    }
+
+    /// Applies a JSON object of settings, or enables or disables the
+    /// channel given `On` or `Off`.
+    fn set(&mut self, value: String) -> Result<(), Error> {
+        let changes = match json::from_str::<json::Value>(&value) {
+            Ok(value) if value.is_object() => Changes::from_json(&value),
+            _ => Changes::from_json(&json::json!({ "enabled": value.trim() })).map_err(|_| ""),
+        }
+        .map_err(Self::invalid_setting)?;
+        self.apply(&changes)
+    }
+
+    fn get(&mut self) -> Result<String, Error> {
+        self.read().map(|settings| settings.to_json().to_string())
+    }
+
+    fn get_resource(&mut self, resource: &str, _query: &Query) -> Result<json::Value, Error> {
+        match resource {
+            "" => self.read().map(|settings| settings.to_json()),
+            setting if SETTINGS.contains(&setting) => Ok(self.read()?.to_json()[setting].clone()),
+            _ => Err(no_such_resource(resource)),
+        }
+    }
+
+    /// Changes the setting named by `resource`, given as a simple string
+    /// such as `20ms`.
+    fn set_resource(
+        &mut self,
+        resource: &str,
+        value: String,
+        _query: &Query,
+    ) -> Result<Option<json::Value>, Error> {
+        match resource {
+            "" => self.set(value).map(|_| None),
+            setting if SETTINGS.contains(&setting) => {
+                let changes = Changes::from_json(&json::json!({ setting: value.trim() }))
+                    .map_err(Self::invalid_setting)?;
+                self.apply(&changes).map(|_| None)
+            }
+            _ => Err(no_such_resource(resource)),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        "pwm"
+    }
+
+    fn to_json(&self) -> json::Value {
+        let mut config = self.initial.to_json();
+        config["chip"] = json::json!(self.chip);
+        config["channel"] = json::json!(self.channel);
+        if let Some(root) = &self.sysfs_root {
+            config["sysfs-root"] = json::Value::String(root.clone());
+        }
+        config
+    }
+}
+
+impl Drop for Pwm {
+    fn drop(&mut self) {
+        if self.exported {
+            let chip_path = self.path.parent().unwrap_or(&self.path);
+            let result = fs::write(chip_path.join("unexport"), self.channel.to_string());
+            if let Err(error) = result {
+                log::error!("Failed to unexport {}: {}", self.path.display(), error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duty_cycle(value: json::Value) -> Option<DutyCycle> {
+        DutyCycle::from_json(&value)
+    }
+
+    #[test]
+    fn duty_cycles_are_fractions_percentages_or_durations() {
+        let period = Duration::from_millis(20);
+        let parsed = |value| duty_cycle(value).map(|d| d.time_in(period));
+        assert_eq!(parsed(json::json!(0.25)), Some(Duration::from_millis(5)));
+        assert_eq!(parsed(json::json!(1)), Some(period));
+        assert_eq!(parsed(json::json!("25%")), Some(Duration::from_millis(5)));
+        assert_eq!(
+            parsed(json::json!("1500us")),
+            Some(Duration::from_micros(1500))
+        );
+        for invalid in &[
+            json::json!(1.5),
+            json::json!(-0.1),
+            json::json!("101%"),
+            json::json!("x%"),
+        ] {
+            assert!(duty_cycle(invalid.clone()).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn duty_cycles_are_given_as_fractions_of_a_period() {
+        let period = Duration::from_millis(20);
+        let fraction = |value| duty_cycle(value).unwrap().fraction_of(period);
+        assert_eq!(fraction(json::json!("5ms")), 0.25);
+        assert_eq!(fraction(json::json!("75%")), 0.75);
+        assert!(fraction(json::json!("30ms")) > 1.0);
+    }
+
+    #[test]
+    fn enabled_is_a_boolean_or_a_word() {
+        for (value, enabled) in &[
+            (json::json!(true), Some(true)),
+            (json::json!("On"), Some(true)),
+            (json::json!("1"), Some(true)),
+            (json::json!(false), Some(false)),
+            (json::json!("Off"), Some(false)),
+            (json::json!("false"), Some(false)),
+            (json::json!(1), None),
+            (json::json!("yes"), None),
+        ] {
+            assert_eq!(enabled_from_json(value), *enabled, "{}", value);
+        }
+    }
+}
@@ -1,86 +1,302 @@
 // Copyright (C) 2020 Arron Speake
 
-use crate::http_status_code::HTTPStatusCode;
-use tiny_http::{Response, ResponseBox};
+use crate::{config_error::ConfigError, http_status_code::HTTPStatusCode, limb};
+use serde_json as json;
+use tiny_http::{Header, Request, Response, ResponseBox};
+
+/// How a response is rendered, chosen by the request's `Accept` header.
+/// Binary responses are only given for limb values, and are otherwise the
+/// same as text responses.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ResponseFormat {
+    Text,
+    Json,
+    Binary,
+}
+
+impl ResponseFormat {
+    pub fn of_request(request: &Request) -> Self {
+        let accepts = |content_type: &str| {
+            request
+                .headers()
+                .iter()
+                .filter(|h| h.field.equiv("Accept"))
+                .any(|h| h.value.as_str().contains(content_type))
+        };
+        if accepts("application/json") {
+            ResponseFormat::Json
+        } else if accepts("application/octet-stream") {
+            ResponseFormat::Binary
+        } else {
+            ResponseFormat::Text
+        }
+    }
+}
 
 pub struct ResponseData {
     pub code: HTTPStatusCode,
+    /// The body of the response in text mode.
     pub content: String,
+    /// Whether `content` is itself a JSON document.
+    pub content_is_json: bool,
+    /// The body of a successful response in JSON mode. Defaults to
+    /// `{"message": content}` when absent.
+    pub json: Option<json::Value>,
+    /// The limb which the response concerns, if any.
+    pub limb: Option<String>,
+    /// The body of a successful response in binary mode, if it has one.
+    pub data: Option<Vec<u8>>,
+    /// Headers added to the response in any mode.
+    pub headers: Vec<Header>,
 }
 
 impl ResponseData {
-    pub fn configure_success() -> Self {
+    fn new(code: HTTPStatusCode, content: &str) -> Self {
         Self {
-            code: HTTPStatusCode::OK,
-            content: "Configuration completed successfullly.".to_owned(),
+            code,
+            content: content.to_owned(),
+            content_is_json: false,
+            json: None,
+            limb: None,
+            data: None,
+            headers: Vec::new(),
         }
     }
 
+    pub fn configure_success() -> Self {
+        Self::new(HTTPStatusCode::OK, "Configuration completed successfullly.")
+    }
+
     pub fn not_found() -> Self {
+        Self::new(HTTPStatusCode::NotFound, "")
+    }
+
+    pub fn limb_not_found() -> Self {
+        Self::new(HTTPStatusCode::NotFound, "That limb does not exist.")
+    }
+
+    pub fn state_not_found() -> Self {
+        Self::new(HTTPStatusCode::NotFound, "No configuration is saved.")
+    }
+
+    pub fn ok(content: &str) -> Self {
+        Self::new(HTTPStatusCode::OK, content)
+    }
+
+    /// A response which is the same JSON document in either mode.
+    pub fn json(value: json::Value) -> Self {
         Self {
-            code: HTTPStatusCode::NotFound,
-            content: "".to_owned(),
+            content: value.to_string(),
+            content_is_json: true,
+            json: Some(value),
+            ..Self::new(HTTPStatusCode::OK, "")
         }
     }
 
-    pub fn limb_not_found() -> Self {
+    /// A response with separate text and JSON renderings.
+    pub fn text_or_json(content: &str, value: json::Value) -> Self {
         Self {
-            code: HTTPStatusCode::NotFound,
-            content: "That limb does not exist.".to_owned(),
+            json: Some(value),
+            ..Self::new(HTTPStatusCode::OK, content)
         }
     }
 
-    pub fn ok(content: &str) -> Self {
+    /// A value read from a limb. Strings are sent as-is in text mode, and
+    /// any other value as a JSON document.
+    pub fn limb_value(value: json::Value) -> Self {
+        let wrapped = json::json!({ "value": value });
+        match value {
+            json::Value::String(s) => Self::text_or_json(&s, wrapped),
+            value => Self {
+                json: Some(wrapped),
+                ..Self::json(value)
+            },
+        }
+    }
+
+    /// Raw bytes read from a limb, for binary mode.
+    pub fn limb_bytes(data: Vec<u8>) -> Self {
         Self {
-            code: HTTPStatusCode::OK,
-            content: content.to_owned(),
+            data: Some(data),
+            ..Self::new(HTTPStatusCode::OK, "")
         }
     }
 
     pub fn bad_request(content: &str) -> Self {
+        Self::new(HTTPStatusCode::BadRequest, content)
+    }
+
+    /// A response listing every problem found in a rejected configuration.
+    pub fn config_errors(errors: &[ConfigError]) -> Self {
+        let value: json::Value = errors.iter().map(ConfigError::to_json).collect();
         Self {
             code: HTTPStatusCode::BadRequest,
-            content: content.to_owned(),
+            ..Self::json(value)
         }
     }
 
-    pub fn method_not_allowed(content: &str) -> Self {
+    /// A response to a method which isn't one of `allowed`, e.g. "GET, POST".
+    pub fn method_not_allowed(allowed: &str) -> Self {
+        let content = format!("Allowed: {}", allowed);
+        let allow = Header::from_bytes(&b"Allow"[..], allowed.as_bytes()).unwrap();
         Self {
-            code: HTTPStatusCode::MethodNotAllowed,
-            content: content.to_owned(),
+            headers: vec![allow],
+            ..Self::new(HTTPStatusCode::MethodNotAllowed, &content)
         }
     }
 
-    pub fn not_implemented(content: &str) -> Self {
-        Self {
-            code: HTTPStatusCode::NotImplemented,
-            content: content.to_owned(),
+    /// A response to a limb operation which failed. `allowed` lists the
+    /// methods to suggest if the limb doesn't support the operation.
+    pub fn limb_error(error: limb::Error, allowed: &str) -> Self {
+        use limb::ErrorKind::*;
+        let code = match error.kind() {
+            InvalidValue => HTTPStatusCode::BadRequest,
+            NoSuchResource => HTTPStatusCode::NotFound,
+            InvalidOperation => HTTPStatusCode::MethodNotAllowed,
+            Busy => HTTPStatusCode::Conflict,
+            Timeout => HTTPStatusCode::GatewayTimeout,
+            BrokenLimb | WriteFailed | ReadFailed => HTTPStatusCode::ServiceUnavailable,
+        };
+        let kind: &'static str = error.kind().into();
+        let source = std::error::Error::source(&error).map(|e| e.to_string());
+        let mut response = Self {
+            json: Some(json::json!({ "kind": kind, "source": source })),
+            limb: error.limb().map(str::to_owned),
+            ..Self::new(code, &error.to_string())
+        };
+        if let InvalidOperation = error.kind() {
+            response.headers = Self::method_not_allowed(allowed).headers;
         }
+        response
+    }
+
+    pub fn internal_server_error(content: &str) -> Self {
+        Self::new(HTTPStatusCode::InternalServerError, content)
     }
 
     pub fn forbidden() -> Self {
-        Self {
-            code: HTTPStatusCode::Forbidden,
-            content: "".to_owned(),
-        }
+        Self::new(HTTPStatusCode::Forbidden, "")
     }
 
     pub fn site_index() -> Self {
-        Self::ok(include_str!("../assets/index.html"))
+        Self::text_or_json(
+            include_str!("../assets/index.html"),
+            json::json!({
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            }),
+        )
     }
-}
 
-impl Into<ResponseBox> for ResponseData {
-    fn into(self) -> tiny_http::ResponseBox {
-        if let HTTPStatusCode::OK = self.code {
+    /// Records the limb which the response concerns.
+    pub fn with_limb(mut self, limb: &str) -> Self {
+        self.limb = Some(limb.to_owned());
+        self
+    }
+
+    /// Adds a limb's annotations of the value being sent, as headers such
+    /// as `X-Phal-Dropped` and as members of the JSON-mode body.
+    pub fn with_annotations(mut self, annotations: json::Map<String, json::Value>) -> Self {
+        for (name, value) in &annotations {
+            let field = format!("X-Phal-{}", title_case(name));
+            let value = match value {
+                json::Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+            if let Ok(header) = Header::from_bytes(field.as_bytes(), value.as_bytes()) {
+                self.headers.push(header);
+            }
+        }
+        if let Some(json::Value::Object(o)) = &mut self.json {
+            o.extend(annotations);
+        }
+        self
+    }
+
+    fn is_success(&self) -> bool {
+        matches!(self.code, HTTPStatusCode::OK)
+    }
+
+    /// The JSON-mode body. Errors share one envelope, with any structured
+    /// detail (e.g. configuration errors) under `details`.
+    fn to_json(&self) -> json::Value {
+        if self.is_success() {
+            let mut value = match &self.json {
+                Some(value) => value.clone(),
+                None => json::json!({ "message": self.content }),
+            };
+            if let (Some(limb), json::Value::Object(o)) = (&self.limb, &mut value) {
+                o.insert("limb".to_owned(), json::Value::String(limb.clone()));
+            }
+            value
+        } else {
+            let message = if self.content_is_json || self.content.is_empty() {
+                self.code.name().to_owned()
+            } else {
+                self.content.clone()
+            };
+            let mut envelope = json::json!({
+                "code": self.code.status_code(),
+                "error": self.code.name(),
+                "message": message,
+                "limb": self.limb,
+            });
+            if let Some(details) = &self.json {
+                envelope["details"] = details.clone();
+            }
+            envelope
+        }
+    }
+
+    pub fn into_response(mut self, format: ResponseFormat) -> ResponseBox {
+        let code = self.code.status_code();
+        let data = match format {
+            ResponseFormat::Binary => self.data.take(),
+            _ => None,
+        };
+        let mut response = if format == ResponseFormat::Json {
+            json_response(self.to_json().to_string(), code)
+        } else if let Some(data) = data {
+            let content_type =
+                Header::from_bytes(&b"Content-Type"[..], &b"application/octet-stream"[..]).unwrap();
+            Response::from_data(data).with_header(content_type).boxed()
+        } else if self.content_is_json {
+            json_response(self.content, code)
+        } else if self.is_success() {
             Response::from_string(self.content).boxed()
         } else {
-            let code = self.code.status_code();
             let name = self.code.name();
             let message = format!("{} {}\n{}", code, name, self.content);
             Response::from_string(message)
                 .with_status_code(code)
                 .boxed()
+        };
+        for header in self.headers {
+            response.add_header(header);
         }
+        response
     }
 }
+
+fn json_response(body: String, code: u16) -> ResponseBox {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    Response::from_data(body.into_bytes())
+        .with_header(content_type)
+        .with_status_code(code)
+        .boxed()
+}
+
+/// Capitalises each word of a hyphenated name, e.g. `dropped-lines` becomes
+/// `Dropped-Lines`.
+fn title_case(name: &str) -> String {
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("-")
+}
@@ -5,53 +5,580 @@
  * Copyright (C) 2020 Callum David O'Brien
  */
 
-use serde_json as json;
 use crate::{
-    limb::{Error, Limb},
-    port_settings_from_json::port_settings_from_json,
+    duration::{format_duration, parse_duration},
+    limb::{no_such_resource, value_bytes, ConfigError, Error, ErrorKind, Limb, Query},
+    pattern::Pattern,
+    pin::level_name,
+    pin_sequence::{parse_sequence, Step},
+    port_settings_from_json::{port_settings_from_json, port_settings_to_json},
+    serial_capture::{Capture, Captured, CapturedLines, Expected, Overflow},
 };
+use nix::{errno::Errno, libc};
+use serde_json as json;
 use serial::{self, SerialPort};
-use std::io::{Read, Write};
+use std::{
+    io::Write,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
 
-pub struct Serial(serial::SystemPort);
+/// The number of received bytes kept between reads by default.
+const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How long a request to `expect` waits unless given a `timeout`.
+const DEFAULT_EXPECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a break lasts unless given a duration.
+const DEFAULT_BREAK: Duration = Duration::from_millis(250);
+
+pub struct Serial {
+    port: Arc<Mutex<serial::SystemPort>>,
+    capture: Capture,
+    device: String,
+    settings: serial::PortSettings,
+    buffer_size: Option<usize>,
+    overflow: Option<Overflow>,
+    mode: Option<Mode>,
+    line_ending: Option<&'static str>,
+    /// The number of bytes dropped before the last read, until reported.
+    dropped: Option<u64>,
+}
 
 impl Limb for Serial {
-    fn from_json(config: &json::Value) -> Option<Self> {
-        let mut port = match &config["device"] {
-            json::Value::String(s) => serial::open(&s).ok().map(Serial),
-            _ => None,
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError> {
+        let device = match &config["device"] {
+            json::Value::String(s) => Ok(s.clone()),
+            _ => Err(ConfigError::new("device", "the path of a serial device")),
         }?;
         let settings = port_settings_from_json(config)?;
-        port.0
-            .reconfigure(&|s| {
-                s.set_baud_rate(settings.baud_rate)?;
-                s.set_char_size(settings.char_size);
-                s.set_parity(settings.parity);
-                s.set_stop_bits(settings.stop_bits);
-                s.set_flow_control(settings.flow_control);
-                Ok(())
-            })
-            .ok();
-        Some(port)
+        let buffer_size = match &config["buffer-size"] {
+            json::Value::Null => Some(None),
+            json::Value::Number(n) => n.as_u64().filter(|&n| n > 0).map(|n| Some(n as usize)),
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("buffer-size", "a number of bytes greater than 0"))?;
+        let overflow = match &config["overflow"] {
+            json::Value::Null => Some(None),
+            json::Value::String(s) => Overflow::from_name(s).map(Some),
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("overflow", "\"drop-oldest\" or \"drop-newest\""))?;
+        let mode = match &config["mode"] {
+            json::Value::Null => Some(None),
+            json::Value::String(s) => Mode::from_name(s).map(Some),
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("mode", "\"raw\" or \"lines\""))?;
+        let line_ending = match &config["line-ending"] {
+            json::Value::Null => Some(None),
+            json::Value::String(s) => match s.as_str() {
+                "\n" => Some(Some("\n")),
+                "\r\n" => Some(Some("\r\n")),
+                "\r" => Some(Some("\r")),
+                _ => None,
+            },
+            _ => None,
+        }
+        .ok_or_else(|| ConfigError::new("line-ending", "\"\\n\", \"\\r\\n\" or \"\\r\""))?;
+        let mut port = serial::open(&device).map_err(|e| {
+            ConfigError::new("device", "a serial device which can be opened").with_serial_source(e)
+        })?;
+        port.reconfigure(&|s| {
+            s.set_baud_rate(settings.baud_rate)?;
+            s.set_char_size(settings.char_size);
+            s.set_parity(settings.parity);
+            s.set_stop_bits(settings.stop_bits);
+            s.set_flow_control(settings.flow_control);
+            Ok(())
+        })
+        .map_err(|e| {
+            ConfigError::new("baud-rate", "a baud rate the device supports").with_serial_source(e)
+        })?;
+        let port = Arc::new(Mutex::new(port));
+        let capture = Capture::new(
+            Arc::clone(&port),
+            buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            overflow.unwrap_or(Overflow::DropOldest),
+        );
+        Ok(Serial {
+            port,
+            capture,
+            device,
+            settings,
+            buffer_size,
+            overflow,
+            mode,
+            line_ending,
+            dropped: None,
+        })
     }
 
     fn set(&mut self, value: String) -> Result<(), Error> {
-        self.0
-            .write_all(value.as_bytes())
-            .map_err(|_| Error::BrokenLimb)
+        self.write(value.as_bytes())
     }
 
+    /// Removes and returns everything received since the last read.
     fn get(&mut self) -> Result<String, Error> {
-        let mut bytes = Vec::new();
-        let _ = self.0.read_to_end(&mut bytes);
-        // ^ Returns error when reaching EOF for some reason. For now, just
-        // ignore the error and return partial/empty result below.
+        self.get_resource("", &Query::default())
+            .map(|value| match value {
+                json::Value::String(s) => s,
+                value => value.to_string(),
+            })
+    }
+
+    /// Reads the data received since the last read, or the lines in lines
+    /// mode, leaving it to be read again if `?peek` is given.
+    fn get_resource(&mut self, resource: &str, query: &Query) -> Result<json::Value, Error> {
+        match (resource, self.mode) {
+            ("", Some(Mode::Lines)) => self.read_lines(query),
+            ("", _) => {
+                let encoding = Encoding::of_query(query)?;
+                let data = self.read(query.contains("peek"))?;
+                Ok(json::Value::String(encoding.encode(&data)))
+            }
+            ("expect", _) => self.expect(query),
+            ("modem", _) => self.modem_lines(),
+            ("break", _) => Err(Error::new(
+                ErrorKind::InvalidOperation,
+                "a break can only be sent",
+            )),
+            _ => match ModemLine::from_name(resource) {
+                Some(line) => {
+                    let level = self.modem_bits()? & line.bit() != 0;
+                    Ok(json::Value::String(level_name(level as u8).to_owned()))
+                }
+                None => Err(no_such_resource(resource)),
+            },
+        }
+    }
 
-        let string = String::from_utf8_lossy(&bytes[..]);
-        Ok(string.into_owned())
+    fn get_resource_bytes(&mut self, resource: &str, query: &Query) -> Result<Vec<u8>, Error> {
+        match (resource, self.mode) {
+            ("", Some(Mode::Lines)) => self.get_resource(resource, query).map(value_bytes),
+            ("", _) => self.read(query.contains("peek")),
+            _ => self.get_resource(resource, query).map(value_bytes),
+        }
+    }
+
+    /// Writes to the port, decoding the value with the `encoding` query
+    /// parameter. A write to `expect` then waits for a response, as a GET of
+    /// `expect` does.
+    ///
+    /// Writes to `dtr` and `rts` set the line, taking the same sequences of
+    /// commands as output pins, e.g. `Pulse Low 100ms`. A write to `break`
+    /// sends a break for the duration given, or 250ms.
+    fn set_resource(
+        &mut self,
+        resource: &str,
+        value: String,
+        query: &Query,
+    ) -> Result<Option<json::Value>, Error> {
+        match resource {
+            "" | "expect" => {
+                let data = Encoding::of_query(query)?.decode(&value)?;
+                self.set_resource_bytes(resource, data, query)
+            }
+            "break" => {
+                let duration = match value.trim() {
+                    "" => Some(DEFAULT_BREAK),
+                    value => parse_duration(value),
+                }
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidValue, "expected a duration such as 250ms")
+                })?;
+                self.send_break(duration).map(|_| None)
+            }
+            _ => match ModemLine::from_name(resource) {
+                Some(line) if line.is_output() => {
+                    let steps = parse_sequence(&value)
+                        .map_err(|message| Error::new(ErrorKind::InvalidValue, message))?;
+                    self.run_sequence(line, &steps).map(|_| None)
+                }
+                Some(line) => {
+                    let message = format!("{} is an input", line.name());
+                    Err(Error::new(ErrorKind::InvalidOperation, message))
+                }
+                None => Err(no_such_resource(resource)),
+            },
+        }
+    }
+
+    fn set_resource_bytes(
+        &mut self,
+        resource: &str,
+        value: Vec<u8>,
+        query: &Query,
+    ) -> Result<Option<json::Value>, Error> {
+        match resource {
+            "" => self.write(&value).map(|_| None),
+            "expect" => {
+                // Checked before writing, so that a bad request has no effect.
+                let pattern = expected_pattern(query)?;
+                let encoding = Encoding::of_query(query)?;
+                self.write(&value)?;
+                self.wait_for(&pattern, query, encoding).map(Some)
+            }
+            _ => {
+                let value = String::from_utf8(value).map_err(|e| {
+                    Error::new(ErrorKind::InvalidValue, "expected UTF-8 text").with_source(e)
+                })?;
+                self.set_resource(resource, value, query)
+            }
+        }
+    }
+
+    fn annotations(&mut self) -> json::Map<String, json::Value> {
+        let mut annotations = json::Map::new();
+        if let Some(dropped) = self.dropped.take() {
+            annotations.insert("dropped".to_owned(), dropped.into());
+        }
+        annotations
     }
 
     fn type_name(&self) -> &'static str {
         "serial"
     }
+
+    fn to_json(&self) -> json::Value {
+        let mut config = port_settings_to_json(&self.settings);
+        config["device"] = json::Value::String(self.device.clone());
+        if let Some(buffer_size) = self.buffer_size {
+            config["buffer-size"] = json::json!(buffer_size);
+        }
+        if let Some(overflow) = self.overflow {
+            config["overflow"] = json::Value::String(overflow.name().to_owned());
+        }
+        if let Some(mode) = self.mode {
+            config["mode"] = json::Value::String(mode.name().to_owned());
+        }
+        if let Some(line_ending) = self.line_ending {
+            config["line-ending"] = json::Value::String(line_ending.to_owned());
+        }
+        config
+    }
+}
+
+impl Serial {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.port
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_all(data)
+            .map_err(|e| {
+                let message = format!("writing {}", self.device);
+                Error::from_io(ErrorKind::WriteFailed, message, e)
+            })
+    }
+
+    fn read(&mut self, peek: bool) -> Result<Vec<u8>, Error> {
+        let captured = if peek {
+            self.capture.peek()
+        } else {
+            self.capture.take()
+        };
+        let Captured { data, dropped } = captured.map_err(|failure| self.read_error(failure))?;
+        self.dropped = Some(dropped);
+        Ok(data)
+    }
+
+    /// The complete lines received since the last read, as an array of
+    /// `{"t": <receive time>, "line": <text>}` objects.
+    fn read_lines(&mut self, query: &Query) -> Result<json::Value, Error> {
+        let encoding = Encoding::of_query(query)?;
+        let ending = self.line_ending.unwrap_or("\n");
+        let CapturedLines { lines, dropped } = self
+            .capture
+            .lines(ending.as_bytes(), query.contains("peek"))
+            .map_err(|failure| self.read_error(failure))?;
+        self.dropped = Some(dropped);
+        Ok(lines
+            .into_iter()
+            .map(|line| json::json!({ "t": line.time, "line": encoding.encode(&line.text) }))
+            .collect())
+    }
+
+    /// The state of the modem lines, as `TIOCM_*` bits.
+    fn modem_bits(&self) -> Result<libc::c_int, Error> {
+        let fd = self
+            .port
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_raw_fd();
+        modem_bits(fd).map_err(|e| {
+            let message = format!("reading the modem lines of {}", self.device);
+            Error::new(ErrorKind::ReadFailed, message).with_source(e)
+        })
+    }
+
+    /// The level of every modem line, as a JSON object.
+    fn modem_lines(&self) -> Result<json::Value, Error> {
+        let bits = self.modem_bits()?;
+        Ok(ModemLine::ALL
+            .iter()
+            .map(|line| {
+                let level = level_name((bits & line.bit() != 0) as u8);
+                (
+                    line.name().to_lowercase(),
+                    json::Value::String(level.to_owned()),
+                )
+            })
+            .collect::<json::Map<String, json::Value>>()
+            .into())
+    }
+
+    fn set_modem_line(&self, line: ModemLine, level: bool) -> Result<(), Error> {
+        let mut port = self.port.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = match line {
+            ModemLine::Dtr => port.set_dtr(level),
+            _ => port.set_rts(level),
+        };
+        result.map_err(|e| {
+            let message = format!("setting {} on {}", line.name(), self.device);
+            Error::new(ErrorKind::WriteFailed, message).with_source(e)
+        })
+    }
+
+    fn run_sequence(&self, line: ModemLine, steps: &[Step]) -> Result<(), Error> {
+        for step in steps {
+            match *step {
+                Step::Set(level) => self.set_modem_line(line, level == 1)?,
+                Step::Toggle => {
+                    let level = self.modem_bits()? & line.bit() != 0;
+                    self.set_modem_line(line, !level)?;
+                }
+                Step::Wait(duration) => thread::sleep(duration),
+            }
+        }
+        Ok(())
+    }
+
+    /// Holds the transmit line low for `duration`. The port is locked for
+    /// the duration, so that nothing is written during the break.
+    fn send_break(&self, duration: Duration) -> Result<(), Error> {
+        let port = self.port.lock().unwrap_or_else(PoisonError::into_inner);
+        let fd = port.as_raw_fd();
+        let error = |e| {
+            let message = format!("sending a break on {}", self.device);
+            Error::new(ErrorKind::WriteFailed, message).with_source(e)
+        };
+        set_break(fd, true).map_err(error)?;
+        thread::sleep(duration);
+        set_break(fd, false).map_err(error)
+    }
+
+    fn read_error(&self, failure: String) -> Error {
+        let message = format!("reading {}", self.device);
+        Error::new(ErrorKind::BrokenLimb, message).with_source(failure)
+    }
+
+    /// Waits for the pattern given by the `text` or `regex` query parameter,
+    /// for up to the `timeout` query parameter.
+    fn expect(&mut self, query: &Query) -> Result<json::Value, Error> {
+        let pattern = expected_pattern(query)?;
+        let encoding = Encoding::of_query(query)?;
+        self.wait_for(&pattern, query, encoding)
+    }
+
+    fn wait_for(
+        &mut self,
+        pattern: &Pattern,
+        query: &Query,
+        encoding: Encoding,
+    ) -> Result<json::Value, Error> {
+        let timeout = query.duration("timeout")?.unwrap_or(DEFAULT_EXPECT_TIMEOUT);
+        let expected = self
+            .capture
+            .expect(pattern, timeout)
+            .map_err(|failure| self.read_error(failure))?;
+        match expected {
+            Some(Expected {
+                before,
+                matched,
+                dropped,
+            }) => {
+                self.dropped = Some(dropped);
+                Ok(json::json!({
+                    "before": encoding.encode(&before),
+                    "match": encoding.encode(&matched),
+                }))
+            }
+            None => {
+                let message = format!(
+                    "waiting {} for a match on {}",
+                    format_duration(timeout),
+                    self.device
+                );
+                Err(Error::new(ErrorKind::Timeout, message))
+            }
+        }
+    }
+}
+
+/// The pattern given by the `text` or `regex` query parameter.
+fn expected_pattern(query: &Query) -> Result<Pattern, Error> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidValue, message);
+    match (query.get("text"), query.get("regex")) {
+        (Some(text), None) if !text.is_empty() => Ok(Pattern::literal(text)),
+        (None, Some(regex)) if !regex.is_empty() => {
+            Pattern::regex(regex).map_err(|e| invalid(format!("invalid regex: {}", e)))
+        }
+        _ => Err(invalid(
+            "expected either a text or a regex parameter".to_owned(),
+        )),
+    }
+}
+
+/// The state of a port's modem lines, as `TIOCM_*` bits.
+fn modem_bits(fd: RawFd) -> nix::Result<libc::c_int> {
+    let mut bits: libc::c_int = 0;
+    // TIOCMGET only writes an int to the pointer it is given.
+    Errno::result(unsafe { libc::ioctl(fd, libc::TIOCMGET, &mut bits) })?;
+    Ok(bits)
+}
+
+/// Starts or stops sending a break.
+fn set_break(fd: RawFd, on: bool) -> nix::Result<()> {
+    let request = if on { libc::TIOCSBRK } else { libc::TIOCCBRK };
+    Errno::result(unsafe { libc::ioctl(fd, request) }).map(drop)
+}
+
+/// A modem control line of a serial port. DTR and RTS are outputs, and the
+/// rest inputs.
+#[derive(Clone, Copy)]
+enum ModemLine {
+    Dtr,
+    Rts,
+    Cts,
+    Dsr,
+    Ri,
+    Dcd,
+}
+
+impl ModemLine {
+    const ALL: [ModemLine; 6] = [
+        ModemLine::Dtr,
+        ModemLine::Rts,
+        ModemLine::Cts,
+        ModemLine::Dsr,
+        ModemLine::Ri,
+        ModemLine::Dcd,
+    ];
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|line| line.name().to_lowercase() == name)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ModemLine::Dtr => "DTR",
+            ModemLine::Rts => "RTS",
+            ModemLine::Cts => "CTS",
+            ModemLine::Dsr => "DSR",
+            ModemLine::Ri => "RI",
+            ModemLine::Dcd => "DCD",
+        }
+    }
+
+    fn bit(self) -> libc::c_int {
+        match self {
+            ModemLine::Dtr => libc::TIOCM_DTR,
+            ModemLine::Rts => libc::TIOCM_RTS,
+            ModemLine::Cts => libc::TIOCM_CTS,
+            ModemLine::Dsr => libc::TIOCM_DSR,
+            ModemLine::Ri => libc::TIOCM_RI,
+            ModemLine::Dcd => libc::TIOCM_CD,
+        }
+    }
+
+    fn is_output(self) -> bool {
+        matches!(self, ModemLine::Dtr | ModemLine::Rts)
+    }
+}
+
+/// Whether data is read as it was received, or split into lines.
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Raw,
+    Lines,
+}
+
+impl Mode {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(Mode::Raw),
+            "lines" => Some(Mode::Lines),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Raw => "raw",
+            Mode::Lines => "lines",
+        }
+    }
+}
+
+/// How data is written in the bodies of requests and responses.
+#[derive(Clone, Copy)]
+enum Encoding {
+    /// UTF-8 text. Received bytes which aren't UTF-8 are replaced with U+FFFD.
+    Text,
+    Hex,
+    Base64,
+}
+
+impl Encoding {
+    /// The encoding given by the `encoding` query parameter, text by default.
+    fn of_query(query: &Query) -> Result<Self, Error> {
+        match query.get("encoding") {
+            None | Some("text") => Ok(Encoding::Text),
+            Some("hex") => Ok(Encoding::Hex),
+            Some("base64") => Ok(Encoding::Base64),
+            Some(_) => Err(Error::new(
+                ErrorKind::InvalidValue,
+                "expected an encoding of text, hex or base64",
+            )),
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> String {
+        match self {
+            Encoding::Text => String::from_utf8_lossy(data).into_owned(),
+            Encoding::Hex => data.iter().map(|b| format!("{:02x}", b)).collect(),
+            Encoding::Base64 => base64::encode(data),
+        }
+    }
+
+    /// Decodes a value, ignoring whitespace in hex and base64.
+    fn decode(self, value: &str) -> Result<Vec<u8>, Error> {
+        let compact: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+        match self {
+            Encoding::Text => Ok(value.as_bytes().to_vec()),
+            Encoding::Hex => {
+                let digits = compact.as_bytes();
+                if !digits.len().is_multiple_of(2) || !digits.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidValue,
+                        "expected pairs of hex digits",
+                    ));
+                }
+                Ok(digits
+                    .chunks(2)
+                    .map(|pair| {
+                        let pair = std::str::from_utf8(pair).unwrap();
+                        u8::from_str_radix(pair, 16).unwrap()
+                    })
+                    .collect())
+            }
+            Encoding::Base64 => base64::decode(&compact)
+                .map_err(|e| Error::new(ErrorKind::InvalidValue, "expected base64").with_source(e)),
+        }
+    }
 }
@@ -0,0 +1,348 @@
+// Copyright (C) 2020 Arron Speake
+
+use crate::pattern::Pattern;
+use nix::poll::{poll, EventFlags, PollFd};
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+    os::unix::io::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How often the capturing thread checks whether it should stop.
+const POLL_INTERVAL_MS: i32 = 100;
+
+/// What happens to data received while the buffer is full.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Overflow {
+    DropOldest,
+    DropNewest,
+}
+
+impl Overflow {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "drop-oldest" => Some(Overflow::DropOldest),
+            "drop-newest" => Some(Overflow::DropNewest),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Overflow::DropOldest => "drop-oldest",
+            Overflow::DropNewest => "drop-newest",
+        }
+    }
+}
+
+/// Data read from the port by a `Capture`, along with the number of bytes
+/// which didn't fit in its buffer.
+pub struct Captured {
+    pub data: Vec<u8>,
+    pub dropped: u64,
+}
+
+/// A line read from the port, without its ending, stamped with the time
+/// its first byte was received in nanoseconds since the Unix epoch.
+pub struct Line {
+    pub time: u64,
+    pub text: Vec<u8>,
+}
+
+/// Complete lines read from the port, along with the number of bytes which
+/// didn't fit in its buffer.
+pub struct CapturedLines {
+    pub lines: Vec<Line>,
+    pub dropped: u64,
+}
+
+/// The data up to and including the first match of a pattern, which has
+/// been removed from the buffer.
+pub struct Expected {
+    pub before: Vec<u8>,
+    pub matched: Vec<u8>,
+    pub dropped: u64,
+}
+
+struct Buffer {
+    data: VecDeque<u8>,
+    capacity: usize,
+    overflow: Overflow,
+    /// The number of bytes dropped since the buffer was last drained.
+    dropped: u64,
+    /// The number of bytes removed from the front of the buffer so far.
+    start: u64,
+    /// When each chunk of data still in the buffer was received, as the
+    /// position of its first byte and its time in nanoseconds since the
+    /// Unix epoch.
+    stamps: VecDeque<(u64, u64)>,
+    /// Why the capturing thread stopped early, if it did.
+    failure: Option<String>,
+}
+
+impl Buffer {
+    fn push(&mut self, data: &[u8]) {
+        let free = self.capacity - self.data.len();
+        let data = match self.overflow {
+            Overflow::DropNewest if data.len() > free => {
+                self.dropped += (data.len() - free) as u64;
+                &data[..free]
+            }
+            _ => data,
+        };
+        if !data.is_empty() {
+            let position = self.start + self.data.len() as u64;
+            self.stamps.push_back((position, now()));
+        }
+        self.data.extend(data);
+        let excess = self.data.len().saturating_sub(self.capacity);
+        if excess > 0 {
+            self.remove(excess);
+            self.dropped += excess as u64;
+        }
+    }
+
+    /// Removes `count` bytes from the front of the buffer.
+    fn remove(&mut self, count: usize) -> Vec<u8> {
+        let removed = self.data.drain(..count).collect();
+        self.start += count as u64;
+        // Keep the stamp of the chunk which the buffer now starts in.
+        while self.stamps.len() > 1 && self.stamps[1].0 <= self.start {
+            self.stamps.pop_front();
+        }
+        removed
+    }
+
+    /// When the byte at `index` in the buffer was received.
+    fn time_of(&self, index: usize) -> u64 {
+        let position = self.start + index as u64;
+        let chunk = self.stamps.partition_point(|&(p, _)| p <= position);
+        self.stamps
+            .get(chunk.saturating_sub(1))
+            .map_or(0, |&(_, time)| time)
+    }
+
+    /// Splits the complete lines at the front of the buffer, returning them
+    /// along with the number of bytes they take up, endings included.
+    fn lines(&mut self, ending: &[u8]) -> (Vec<Line>, usize) {
+        let mut lines = Vec::new();
+        let mut line_start = 0;
+        let data = self.data.make_contiguous();
+        let mut index = 0;
+        while index + ending.len() <= data.len() {
+            if data[index..].starts_with(ending) {
+                lines.push((line_start, data[line_start..index].to_vec()));
+                index += ending.len();
+                line_start = index;
+            } else {
+                index += 1;
+            }
+        }
+        let lines = lines
+            .into_iter()
+            .map(|(start, text)| Line {
+                time: self.time_of(start),
+                text,
+            })
+            .collect();
+        (lines, line_start)
+    }
+
+    fn contents(&self) -> Result<Captured, String> {
+        match &self.failure {
+            Some(failure) if self.data.is_empty() => Err(failure.clone()),
+            _ => Ok(Captured {
+                data: self.data.iter().copied().collect(),
+                dropped: self.dropped,
+            }),
+        }
+    }
+}
+
+struct Shared {
+    buffer: Mutex<Buffer>,
+    changed: Condvar,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Buffer> {
+        self.buffer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Reads everything a port receives on a background thread into a bounded
+/// buffer, so that nothing is lost between requests.
+pub struct Capture {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Capture {
+    /// Starts capturing from `port`, which is only locked while there is
+    /// data to read, so that it can still be written to in the meantime.
+    pub fn new<P>(port: Arc<Mutex<P>>, capacity: usize, overflow: Overflow) -> Self
+    where
+        P: Read + AsRawFd + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            buffer: Mutex::new(Buffer {
+                data: VecDeque::new(),
+                capacity,
+                overflow,
+                dropped: 0,
+                start: 0,
+                stamps: VecDeque::new(),
+                failure: None,
+            }),
+            changed: Condvar::new(),
+            stop: AtomicBool::new(false),
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || capture(&port, &shared))
+        };
+        Capture {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Removes and returns everything captured since the last call.
+    pub fn take(&self) -> Result<Captured, String> {
+        let mut buffer = self.shared.lock();
+        let captured = buffer.contents()?;
+        let length = buffer.data.len();
+        buffer.remove(length);
+        buffer.dropped = 0;
+        Ok(captured)
+    }
+
+    /// Returns the complete lines, ending with `ending`, captured since the
+    /// last call. A line still being received is left for the next call, as
+    /// are the lines returned if `peek` is set.
+    pub fn lines(&self, ending: &[u8], peek: bool) -> Result<CapturedLines, String> {
+        let mut buffer = self.shared.lock();
+        let (lines, length) = buffer.lines(ending);
+        if let (true, Some(failure)) = (lines.is_empty(), &buffer.failure) {
+            return Err(failure.clone());
+        }
+        let dropped = buffer.dropped;
+        if !peek {
+            buffer.remove(length);
+            buffer.dropped = 0;
+        }
+        Ok(CapturedLines { lines, dropped })
+    }
+
+    /// Returns everything captured since the last `take`, leaving it in the
+    /// buffer.
+    pub fn peek(&self) -> Result<Captured, String> {
+        self.shared.lock().contents()
+    }
+
+    /// Waits for `pattern` to appear in the captured data, returning `None`
+    /// if it hasn't within `timeout`. Data before the match is left in the
+    /// buffer if there is no match. A timeout too long to represent is
+    /// treated as no timeout.
+    pub fn expect(&self, pattern: &Pattern, timeout: Duration) -> Result<Option<Expected>, String> {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut buffer = self.shared.lock();
+        loop {
+            if let Some((start, end)) = pattern.find(buffer.data.make_contiguous()) {
+                let mut before = buffer.remove(end);
+                let matched = before.split_off(start);
+                let dropped = std::mem::take(&mut buffer.dropped);
+                return Ok(Some(Expected {
+                    before,
+                    matched,
+                    dropped,
+                }));
+            }
+            if let Some(failure) = &buffer.failure {
+                return Err(failure.clone());
+            }
+            buffer = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    self.shared
+                        .changed
+                        .wait_timeout(buffer, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .shared
+                    .changed
+                    .wait(buffer)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The time now, in nanoseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+fn capture<P: Read + AsRawFd>(port: &Mutex<P>, shared: &Shared) {
+    let fd = port
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_raw_fd();
+    let mut data = [0; 4096];
+    while !shared.stop.load(Ordering::Relaxed) {
+        let mut fds = [PollFd::new(fd, EventFlags::POLLIN)];
+        let result = match poll(&mut fds, POLL_INTERVAL_MS) {
+            Ok(0) => continue,
+            Ok(_) => port
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .read(&mut data),
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(e) => Err(io::Error::other(e.to_string())),
+        };
+        let mut buffer = shared.lock();
+        match result {
+            Ok(0) => {
+                // The port is readable but has no data: it has been closed,
+                // e.g. by a USB adapter being unplugged.
+                let failure = "the device was disconnected".to_owned();
+                log::error!("Stopped reading a serial port: {}", failure);
+                buffer.failure = Some(failure);
+                shared.changed.notify_all();
+                return;
+            }
+            Ok(n) => buffer.push(&data[..n]),
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => continue,
+            Err(error) => {
+                log::error!("Stopped reading a serial port: {}", error);
+                buffer.failure = Some(error.to_string());
+                shared.changed.notify_all();
+                return;
+            }
+        }
+        shared.changed.notify_all();
+    }
+}
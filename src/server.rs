@@ -5,15 +5,36 @@
  * Copyright (C) 2020 Callum David O'Brien
  */
 
-use crate::limb::{Limb, LimbBindings, LimbTypes};
-use crate::response_data::ResponseData;
-use std::net::ToSocketAddrs;
+use crate::limb::{ConfigChange, ConfigError, Error, Limb, LimbBindings, LimbTypes, Query};
+use crate::response_data::{ResponseData, ResponseFormat};
+use serde_json as json;
+use std::{
+    fs, io,
+    net::ToSocketAddrs,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    thread,
+};
 use tiny_http::*;
 
+/// The number of requests handled at once, unless set with `with_workers`.
+pub const DEFAULT_WORKERS: usize = 8;
+
+/// Serves requests from a pool of worker threads. Requests for different
+/// limbs are handled in parallel, while requests for the same limb wait for
+/// each other. Configuration changes only wait for requests using the limbs
+/// they replace, remove or reset, and don't hold up requests to other limbs
+/// while they do.
 pub struct PHALServer {
     types: LimbTypes,
-    limbs: LimbBindings,
+    limbs: RwLock<LimbBindings>,
+    /// Held while the configuration is being changed, so that one change
+    /// is finished before the next begins.
+    changing: Mutex<()>,
     server: Option<Server>,
+    workers: usize,
+    state_file: Option<PathBuf>,
 }
 
 type PHALServerError =
@@ -21,26 +42,139 @@ type PHALServerError =
 
 impl PHALServer {
     pub fn new(types: LimbTypes, address: impl ToSocketAddrs) -> Result<Self, PHALServerError> {
-        let limbs = LimbBindings::new();
+        let limbs = RwLock::new(LimbBindings::new());
         Server::http(address).map(|server| Self {
             types,
             limbs,
+            changing: Mutex::new(()),
             server: Some(server),
+            workers: DEFAULT_WORKERS,
+            state_file: None,
         })
     }
 
+    /// Applies a configuration, as if it had been POSTed to `/config`.
+    pub fn configure(&self, config: &str) -> Result<(), Vec<ConfigError>> {
+        self.change_configuration(ConfigChange::Reconfigure(config))
+    }
+
+    /// Sets a file to which the configuration is saved after every change,
+    /// so that it can be restored with `restore_state` after a restart.
+    pub fn with_state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// Applies the configuration saved in the state file, if there is one,
+    /// setting each limb to its `init` value. Returns whether a saved
+    /// configuration was found.
+    ///
+    /// Limbs which can't be restored, e.g. because their device has gone
+    /// away, are left out and the rest are restored, with an error for each
+    /// limb left out. Errors which don't belong to a limb mean that the
+    /// file couldn't be used at all, and nothing was restored.
+    pub fn restore_state(&self) -> Result<bool, Vec<ConfigError>> {
+        let path = match &self.state_file {
+            Some(path) if path.exists() => path,
+            _ => return Ok(false),
+        };
+        let mut config = fs::read_to_string(path).map_err(|error| {
+            let expected = format!("a readable state file at {}", path.display());
+            vec![ConfigError::root(&expected).with_source(error)]
+        })?;
+        let mut limbs = self.write_limbs();
+        let mut left_out = Vec::new();
+        while let Err(errors) = limbs.reconfigure(&config, &self.types) {
+            let failed: Vec<String> = errors
+                .iter()
+                .filter_map(|e| e.limb().map(str::to_owned))
+                .collect();
+            left_out.extend(errors);
+            let mut remaining = match json::from_str(&config) {
+                Ok(json::Value::Object(o)) if !failed.is_empty() => o,
+                _ => return Err(left_out),
+            };
+            for name in failed {
+                remaining.remove(&name);
+            }
+            config = json::Value::Object(remaining).to_string();
+        }
+        if left_out.is_empty() {
+            Ok(true)
+        } else {
+            Err(left_out)
+        }
+    }
+
+    /// Sets the number of requests which can be handled at once.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
     pub fn run(mut self) {
-        let server = self.server.take().unwrap();
+        let server = Arc::new(self.server.take().unwrap());
+        let workers = self.workers;
+        let this = Arc::new(self);
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                let server = Arc::clone(&server);
+                let this = Arc::clone(&this);
+                thread::spawn(move || this.serve(&server))
+            })
+            .collect();
+        for handle in handles {
+            let _ = handle.join();
+        }
+    }
+
+    fn serve(&self, server: &Server) {
         for mut request in server.incoming_requests() {
-            let response = self.handle_request(&mut request);
+            let format = ResponseFormat::of_request(&request);
+            let response = self.handle_request_guarded(&mut request);
             Self::log_response(&request, &response);
-            let result = request.respond(response.into());
+            let result = request.respond(response.into_response(format));
             if result.is_err() {
-                eprintln!("Failed to respond to request.");
+                log::error!("Failed to respond to request.");
             }
         }
     }
 
+    /// Makes a change to the limbs, saving the resulting configuration to
+    /// the state file if the change succeeds. The bindings aren't locked
+    /// while waiting for the limbs being released or reset, so that
+    /// requests to other limbs carry on in the meantime.
+    fn change_configuration(&self, change: ConfigChange) -> Result<(), Vec<ConfigError>> {
+        let _changing = self.changing.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut pending = self.write_limbs().begin(change, &self.types)?;
+        pending.release();
+        let mut limbs = self.write_limbs();
+        limbs.finish(pending, &self.types)?;
+        self.save_state(&limbs);
+        Ok(())
+    }
+
+    fn save_state(&self, limbs: &LimbBindings) {
+        if let Some(path) = &self.state_file {
+            // Write to a temporary file first, so that a crash part-way
+            // through never leaves a truncated state file behind.
+            let temporary = path.with_extension("tmp");
+            let result = fs::write(&temporary, limbs.to_json().to_string())
+                .and_then(|_| fs::rename(&temporary, path));
+            if let Err(error) = result {
+                log::error!("Failed to save state to {}: {}", path.display(), error);
+            }
+        }
+    }
+
+    fn read_limbs(&self) -> RwLockReadGuard<'_, LimbBindings> {
+        self.limbs.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_limbs(&self) -> RwLockWriteGuard<'_, LimbBindings> {
+        self.limbs.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
     pub fn run_new(types: LimbTypes, address: impl ToSocketAddrs) -> Result<(), PHALServerError> {
         let server = Self::new(types, address)?;
         server.run();
@@ -48,7 +182,7 @@ impl PHALServer {
     }
 
     fn log_response(request: &Request, response: &ResponseData) {
-        println!(
+        log::info!(
             "[{}] {} {} ~ {} {}",
             request.remote_addr().ip(),
             request.method(),
@@ -58,104 +192,260 @@ impl PHALServer {
         )
     }
 
-    fn handle_limb_get_request(limb: &mut Box<dyn Limb>) -> ResponseData {
-        match limb.get() {
-            Ok(value) => ResponseData::ok(value.as_str()),
-            Err(error) => ResponseData::bad_request(error.into()),
+    /// Reports a failed limb operation in the log and to the client.
+    fn limb_error(name: &str, error: Error, allowed: &str) -> ResponseData {
+        let error = error.in_limb(name);
+        log::warn!("{}", error);
+        ResponseData::limb_error(error, allowed)
+    }
+
+    /// Reads a resource of a limb, as raw bytes if the request accepts
+    /// `application/octet-stream`.
+    fn handle_limb_get_request(
+        name: &str,
+        limb: &mut Box<dyn Limb>,
+        resource: &str,
+        request: &Request,
+        query: &Query,
+    ) -> ResponseData {
+        let response = match ResponseFormat::of_request(request) {
+            ResponseFormat::Binary => limb
+                .get_resource_bytes(resource, query)
+                .map(ResponseData::limb_bytes),
+            _ => limb
+                .get_resource(resource, query)
+                .map(ResponseData::limb_value),
+        };
+        match response {
+            Ok(response) => response.with_annotations(limb.annotations()),
+            Err(error) => Self::limb_error(name, error, "POST"),
         }
     }
 
-    fn set_limb_value(limb: &mut Box<dyn Limb>, value: String) -> ResponseData {
-        match limb.set(value) {
-            Ok(_) => ResponseData::ok("Limb successfully updated."),
-            Err(error) => ResponseData::bad_request(error.into()),
+    fn set_limb_value(
+        name: &str,
+        limb: &mut Box<dyn Limb>,
+        resource: &str,
+        value: Vec<u8>,
+        binary: bool,
+        query: &Query,
+    ) -> ResponseData {
+        let result = if binary {
+            limb.set_resource_bytes(resource, value, query)
+        } else {
+            match String::from_utf8(value) {
+                Ok(value) => limb.set_resource(resource, value, query),
+                Err(_) => {
+                    return ResponseData::bad_request(
+                        "The request body isn't UTF-8 text. Send binary data as application/octet-stream.",
+                    )
+                }
+            }
+        };
+        match result {
+            Ok(Some(value)) => ResponseData::limb_value(value),
+            Ok(None) => ResponseData::ok("Limb successfully updated."),
+            Err(error) => Self::limb_error(name, error, "GET"),
         }
     }
 
-    fn handle_limb_post_request(limb: &mut Box<dyn Limb>, request: &mut Request) -> ResponseData {
-        let mut value = String::new();
-        let result = request.as_reader().read_to_string(&mut value);
+    /// Writes to a resource of a limb, as raw bytes if the body is sent as
+    /// `application/octet-stream`.
+    fn handle_limb_post_request(
+        name: &str,
+        limb: &mut Box<dyn Limb>,
+        resource: &str,
+        request: &mut Request,
+        query: &Query,
+    ) -> ResponseData {
+        let binary = request
+            .headers()
+            .iter()
+            .filter(|h| h.field.equiv("Content-Type"))
+            .any(|h| h.value.as_str().starts_with("application/octet-stream"));
+        let mut value = Vec::new();
+        let result = request.as_reader().read_to_end(&mut value);
         match result {
-            Ok(_) => Self::set_limb_value(limb, value),
+            Ok(_) => Self::set_limb_value(name, limb, resource, value, binary, query),
             Err(_) => ResponseData::bad_request("Failed to read request"),
         }
     }
 
-    fn handle_limb_request(limb: &mut Box<dyn Limb>, request: &mut Request) -> ResponseData {
+    /// Handles a request to `/limb/<name>/<resource>`, where the resource is
+    /// empty for requests to the limb itself.
+    fn handle_limb_request(
+        name: &str,
+        limb: &mut Box<dyn Limb>,
+        resource: &str,
+        request: &mut Request,
+        query: &Query,
+    ) -> ResponseData {
         match request.method() {
-            Method::Get => Self::handle_limb_get_request(limb),
-            Method::Post => Self::handle_limb_post_request(limb, request),
-            _ => ResponseData::method_not_allowed("Allowed: GET, POST"),
+            Method::Get => Self::handle_limb_get_request(name, limb, resource, request, query),
+            Method::Post => Self::handle_limb_post_request(name, limb, resource, request, query),
+            _ => ResponseData::method_not_allowed("GET, POST"),
+        }
+    }
+
+    fn handle_config_get_request(&self) -> ResponseData {
+        ResponseData::json(self.read_limbs().to_json())
+    }
+
+    fn configuration_result(result: Result<(), Vec<ConfigError>>) -> ResponseData {
+        match result {
+            Ok(()) => ResponseData::configure_success(),
+            Err(errors) => ResponseData::config_errors(&errors),
         }
     }
 
-    fn handle_config_get_request() -> ResponseData {
-        ResponseData::not_implemented("Configuration retrieval is not yet implemented.")
+    fn handle_config_post_request(&self, request: &mut Request) -> ResponseData {
+        let mut config = String::new();
+        let result = request.as_reader().read_to_string(&mut config);
+        match result {
+            Ok(_) => Self::configuration_result(
+                self.change_configuration(ConfigChange::Reconfigure(&config)),
+            ),
+            Err(_) => ResponseData::bad_request("Failed to read request"),
+        }
     }
 
-    fn update_limb_configuration(&mut self, config: String) -> ResponseData {
-        // For reasons beyond me, from_json fails if limbs is not first cleared.
-        self.limbs.clear();
-        match LimbBindings::from_json(&config, &self.types) {
-            Some(new_limbs) => {
-                self.limbs = new_limbs;
-                ResponseData::configure_success()
+    fn handle_config_patch_request(&self, request: &mut Request) -> ResponseData {
+        let mut config = String::new();
+        let result = request.as_reader().read_to_string(&mut config);
+        match result {
+            Ok(_) => {
+                Self::configuration_result(self.change_configuration(ConfigChange::Patch(&config)))
             }
-            None => ResponseData::bad_request("The provided configuration was ill-formed."),
+            Err(_) => ResponseData::bad_request("Failed to read request"),
         }
     }
 
-    fn handle_config_post_request(&mut self, request: &mut Request) -> ResponseData {
+    fn handle_limb_config_put_request(&self, name: &str, request: &mut Request) -> ResponseData {
         let mut config = String::new();
         let result = request.as_reader().read_to_string(&mut config);
         match result {
-            Ok(_) => self.update_limb_configuration(config),
+            Ok(_) => Self::configuration_result(
+                self.change_configuration(ConfigChange::Insert(name, &config)),
+            ),
             Err(_) => ResponseData::bad_request("Failed to read request"),
         }
     }
 
-    fn handle_config_request(&mut self, request: &mut Request) -> ResponseData {
+    fn handle_limb_config_delete_request(&self, name: &str) -> ResponseData {
+        // Removing a limb can only fail if there is no such limb.
+        match self.change_configuration(ConfigChange::Remove(name)) {
+            Ok(()) => ResponseData::ok("Limb successfully removed."),
+            Err(_) => ResponseData::limb_not_found(),
+        }
+    }
+
+    fn handle_config_request<'a, I>(&self, mut url: I, request: &mut Request) -> ResponseData
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        match url.next() {
+            Some(name) => match request.method() {
+                Method::Put => self.handle_limb_config_put_request(name, request),
+                Method::Delete => self.handle_limb_config_delete_request(name),
+                _ => ResponseData::method_not_allowed("PUT, DELETE"),
+            }
+            .with_limb(name),
+            None => match request.method() {
+                Method::Get => self.handle_config_get_request(),
+                Method::Post => self.handle_config_post_request(request),
+                Method::Patch => self.handle_config_patch_request(request),
+                _ => ResponseData::method_not_allowed("GET, POST, PATCH"),
+            },
+        }
+    }
+
+    fn handle_state_get_request(path: &Path) -> ResponseData {
+        match fs::read_to_string(path).map(|state| json::from_str(&state)) {
+            Ok(Ok(state)) => ResponseData::json(state),
+            Ok(Err(_)) => ResponseData::internal_server_error("The saved state is not valid JSON."),
+            Err(_) => ResponseData::state_not_found(),
+        }
+    }
+
+    fn handle_state_delete_request(path: &Path) -> ResponseData {
+        match fs::remove_file(path) {
+            Ok(()) => ResponseData::ok("Saved state successfully cleared."),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                ResponseData::state_not_found()
+            }
+            Err(error) => {
+                log::error!("Failed to remove {}: {}", path.display(), error);
+                ResponseData::internal_server_error("The saved state could not be cleared.")
+            }
+        }
+    }
+
+    fn handle_state_request(&self, request: &Request) -> ResponseData {
+        let path = match &self.state_file {
+            Some(path) => path,
+            None => return ResponseData::state_not_found(),
+        };
         match request.method() {
-            Method::Get => Self::handle_config_get_request(),
-            Method::Post => self.handle_config_post_request(request),
-            _ => ResponseData::bad_request("Allowed: GET, POST"),
+            Method::Get => Self::handle_state_get_request(path),
+            Method::Delete => Self::handle_state_delete_request(path),
+            _ => ResponseData::method_not_allowed("GET, DELETE"),
         }
     }
 
-    fn try_handle_limb_request<'a, I>(&mut self, mut url: I, request: &mut Request) -> ResponseData
+    fn try_handle_limb_request<'a, I>(
+        &self,
+        mut url: I,
+        request: &mut Request,
+        query: &Query,
+    ) -> ResponseData
     where
         I: Iterator<Item = &'a str>,
     {
         match url.next() {
             Some(limb_name) => {
-                if let Some(limb) = self.limbs.get(limb_name) {
-                    Self::handle_limb_request(limb, request)
-                } else {
-                    ResponseData::limb_not_found()
-                }
+                let resource: Vec<&str> = url.collect();
+                let resource = resource.join("/");
+                // Let go of the bindings before waiting for the limb, so that
+                // a long operation doesn't hold up configuration changes.
+                let limb = self.read_limbs().get(limb_name);
+                let response = match limb {
+                    Some(limb) => match limb.lock().as_mut() {
+                        Some(limb) => {
+                            Self::handle_limb_request(limb_name, limb, &resource, request, query)
+                        }
+                        // The limb was released while waiting for it.
+                        None => ResponseData::limb_not_found(),
+                    },
+                    None => ResponseData::limb_not_found(),
+                };
+                response.with_limb(limb_name)
             }
             None => ResponseData::forbidden(),
         }
     }
 
     fn handle_info_types_request(&self) -> ResponseData {
+        let mut names: Vec<&String> = self.types.names().collect();
+        names.sort();
         let mut content = String::new();
-        for name in self.types.names() {
+        for name in names.iter() {
             content += &format!("{}\n", name);
         }
-        ResponseData::ok(content.as_str())
+        ResponseData::text_or_json(content.as_str(), json::json!(names))
     }
 
     fn handle_info_limbs_request(&self) -> ResponseData {
         let mut content = String::new();
-        for (key, value) in self.limbs.iter() {
+        let mut types = json::Map::new();
+        for (key, type_name) in self.read_limbs().type_names() {
             let name: String = key.escape_default().collect();
-            content += &format!("\"{}\": {}\n", name, value.type_name());
+            content += &format!("\"{}\": {}\n", name, type_name);
+            types.insert(key.clone(), json::Value::String(type_name.to_owned()));
         }
-        ResponseData::ok(content.as_str())
+        ResponseData::text_or_json(content.as_str(), json::Value::Object(types))
     }
 
-    fn handle_info_request<'a, I>(&mut self, mut url: I) -> ResponseData
+    fn handle_info_request<'a, I>(&self, mut url: I) -> ResponseData
     where
         I: Iterator<Item = &'a str>,
     {
@@ -167,15 +457,29 @@ impl PHALServer {
         }
     }
 
-    fn handle_request(&mut self, req: &mut Request) -> ResponseData {
+    /// Handles a request, turning a panic (e.g. from a misbehaving limb)
+    /// into an error response rather than letting it stop the server.
+    fn handle_request_guarded(&self, req: &mut Request) -> ResponseData {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.handle_request(req)));
+        result.unwrap_or_else(|_| {
+            ResponseData::internal_server_error("The request could not be completed.")
+        })
+    }
+
+    fn handle_request(&self, req: &mut Request) -> ResponseData {
         let url_string = req.url().to_owned();
-        let mut url = url_string.split('/').filter(|s| !s.is_empty());
+        let (path, query) = match url_string.find('?') {
+            Some(i) => (&url_string[..i], Query::parse(&url_string[i + 1..])),
+            None => (url_string.as_str(), Query::default()),
+        };
+        let mut url = path.split('/').filter(|s| !s.is_empty());
         match url.next() {
-            Some("limb") => self.try_handle_limb_request(url, req),
-            Some("config") => self.handle_config_request(req),
+            Some("limb") => self.try_handle_limb_request(url, req, &query),
+            Some("config") => self.handle_config_request(url, req),
             Some("info") => self.handle_info_request(url),
+            Some("state") => self.handle_state_request(req),
             Some(_) => ResponseData::not_found(),
             None => ResponseData::site_index(),
         }
     }
-}
\ No newline at end of file
+}
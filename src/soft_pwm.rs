@@ -0,0 +1,368 @@
+// Copyright (C) 2020 Arron Speake
+
+use crate::{
+    limb::{no_such_resource, ConfigError, Error, ErrorKind, Limb, Query},
+    pin::LineConfig,
+    pwm::{enabled_from_json, DutyCycle, DUTY_CYCLE_EXPECTED, ENABLED_EXPECTED},
+};
+use gpio_cdev as cdev;
+use serde_json as json;
+use std::{
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// The highest frequency which can be generated with reasonable accuracy
+/// by sleeping between edges.
+const MAX_FREQUENCY: f64 = 1000.0;
+
+#[derive(Clone, Copy, PartialEq)]
+struct Settings {
+    /// In Hz.
+    frequency: f64,
+    /// The fraction of each period for which the line is high, from 0 to 1.
+    duty_cycle: f64,
+    /// Whether the signal is being generated. The line rests low otherwise.
+    enabled: bool,
+}
+
+impl Settings {
+    /// Changes the settings given in a JSON object, returning the name of
+    /// the first member which is invalid. The duty cycle and whether the
+    /// signal is enabled are given as they are to a `pwm` limb.
+    fn update_from_json(&mut self, value: &json::Value) -> Result<(), &'static str> {
+        if !value.is_object() {
+            return Err("");
+        }
+        if !value["frequency"].is_null() {
+            self.frequency = value["frequency"].as_f64().ok_or("frequency")?;
+        }
+        if !value["duty-cycle"].is_null() {
+            let duty_cycle = DutyCycle::from_json(&value["duty-cycle"]).ok_or("duty-cycle")?;
+            let period =
+                Duration::try_from_secs_f64(1.0 / self.frequency).map_err(|_| "frequency")?;
+            self.duty_cycle = duty_cycle.fraction_of(period);
+        }
+        if !value["enabled"].is_null() {
+            self.enabled = enabled_from_json(&value["enabled"]).ok_or("enabled")?;
+        }
+        self.check()
+    }
+
+    /// Changes the settings given as words such as `50Hz`, `25%`, `On` and
+    /// `Off`, returning the name of the first setting which is invalid.
+    fn update_from_words(&mut self, words: &str) -> Result<(), &'static str> {
+        for word in words.split_whitespace() {
+            if let Some(frequency) = word.strip_suffix("Hz") {
+                self.frequency = frequency.parse().map_err(|_| "frequency")?;
+            } else if let Some(percentage) = word.strip_suffix('%') {
+                let percentage: f64 = percentage.parse().map_err(|_| "duty-cycle")?;
+                self.duty_cycle = percentage / 100.0;
+            } else if word == "On" {
+                self.enabled = true;
+            } else if word == "Off" {
+                self.enabled = false;
+            } else {
+                return Err("");
+            }
+        }
+        self.check()
+    }
+
+    fn check(&self) -> Result<(), &'static str> {
+        if !(self.frequency > 0.0 && self.frequency <= MAX_FREQUENCY) {
+            Err("frequency")
+        } else if !(0.0..=1.0).contains(&self.duty_cycle) {
+            Err("duty-cycle")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn expected(field: &str) -> &'static str {
+        match field {
+            "frequency" => "a frequency in Hz, greater than 0 and at most 1000",
+            "duty-cycle" => DUTY_CYCLE_EXPECTED,
+            "enabled" => ENABLED_EXPECTED,
+            _ => "a JSON object of settings, or settings such as 50Hz 25% On",
+        }
+    }
+
+    fn to_json(self) -> json::Value {
+        json::json!({
+            "frequency": self.frequency,
+            "duty-cycle": self.duty_cycle,
+            "enabled": self.enabled,
+        })
+    }
+}
+
+struct State {
+    settings: Settings,
+    stop: bool,
+    /// Why the signal stopped early, if it did.
+    failure: Option<String>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A PWM signal generated on a GPIO line by a background thread, for slow
+/// signals on lines without PWM hardware.
+pub struct SoftPwm {
+    config: LineConfig,
+    initial: Settings,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Limb for SoftPwm {
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError> {
+        Self::from_json_named("", config)
+    }
+
+    fn from_json_named(name: &str, config: &json::Value) -> Result<Self, ConfigError> {
+        let mut initial = Settings {
+            frequency: 0.0,
+            duty_cycle: 0.5,
+            enabled: true,
+        };
+        if config["frequency"].is_null() {
+            return Err(ConfigError::new(
+                "frequency",
+                Settings::expected("frequency"),
+            ));
+        }
+        initial
+            .update_from_json(config)
+            .map_err(|field| ConfigError::new(field, Settings::expected(field)))?;
+        let line_config = LineConfig::from_json(name, config)?;
+        let handle = line_config.request(cdev::LineRequestFlags::OUTPUT, 0)?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                settings: initial,
+                stop: false,
+                failure: None,
+            }),
+            changed: Condvar::new(),
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            let line_config = line_config.clone();
+            thread::spawn(move || generate(&handle, &shared, &line_config))
+        };
+        Ok(SoftPwm {
+            config: line_config,
+            initial,
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Changes the settings given as a JSON object or as words such as
+    /// `50Hz 25% On`. Settings which aren't given are left as they are.
+    fn set(&mut self, value: String) -> Result<(), Error> {
+        let mut state = self.shared.lock();
+        let mut settings = state.settings;
+        let result = match json::from_str::<json::Value>(&value) {
+            Ok(value) => settings.update_from_json(&value),
+            Err(_) => settings.update_from_words(&value),
+        };
+        result.map_err(|field| {
+            let message = format!("expected {}", Settings::expected(field));
+            Error::new(ErrorKind::InvalidValue, message)
+        })?;
+        state.settings = settings;
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn get(&mut self) -> Result<String, Error> {
+        self.settings().map(|settings| settings.to_string())
+    }
+
+    fn get_resource(&mut self, resource: &str, _query: &Query) -> Result<json::Value, Error> {
+        match resource {
+            "" => self.settings(),
+            _ => Err(no_such_resource(resource)),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        "soft-pwm"
+    }
+
+    fn config_defaults() -> json::Value {
+        let mut defaults = LineConfig::defaults();
+        defaults["duty-cycle"] = json::json!(0.5);
+        defaults["enabled"] = json::Value::Bool(true);
+        defaults
+    }
+
+    fn to_json(&self) -> json::Value {
+        let mut config = self.config.to_json();
+        if let (json::Value::Object(config), json::Value::Object(settings)) =
+            (&mut config, self.initial.to_json())
+        {
+            config.extend(settings);
+        }
+        config
+    }
+}
+
+impl SoftPwm {
+    /// The current settings, as a JSON object.
+    fn settings(&self) -> Result<json::Value, Error> {
+        let state = self.shared.lock();
+        match &state.failure {
+            Some(failure) => Err(Error::new(ErrorKind::BrokenLimb, "generating the signal")
+                .with_source(failure.clone())),
+            None => Ok(state.settings.to_json()),
+        }
+    }
+}
+
+impl Drop for SoftPwm {
+    fn drop(&mut self) {
+        self.shared.lock().stop = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Drives the line with the current settings until told to stop. Each
+/// edge is scheduled from the start of its period, so that the time taken
+/// to set the line doesn't accumulate.
+fn generate(handle: &cdev::LineHandle, shared: &Shared, config: &LineConfig) {
+    let mut state = shared.lock();
+    let mut period_start = Instant::now();
+    while !state.stop {
+        let settings = state.settings;
+        let on = settings.enabled && settings.duty_cycle > 0.0;
+        if let Err(e) = handle.set_value(on as u8) {
+            return fail(
+                &mut state,
+                config.line_error(ErrorKind::WriteFailed, "setting", e),
+            );
+        }
+        if !on || settings.duty_cycle == 1.0 {
+            // The level is constant until the settings change.
+            state = shared
+                .changed
+                .wait_while(state, |s| !s.stop && s.settings == settings)
+                .unwrap_or_else(PoisonError::into_inner);
+            period_start = Instant::now();
+            continue;
+        }
+        let period = Duration::from_secs_f64(1.0 / settings.frequency);
+        let high = period.mul_f64(settings.duty_cycle);
+        let (guard, elapsed) = sleep_until(shared, state, settings, period_start + high);
+        state = guard;
+        if !elapsed {
+            period_start = Instant::now();
+            continue;
+        }
+        if let Err(e) = handle.set_value(0) {
+            return fail(
+                &mut state,
+                config.line_error(ErrorKind::WriteFailed, "setting", e),
+            );
+        }
+        let (guard, elapsed) = sleep_until(shared, state, settings, period_start + period);
+        state = guard;
+        period_start += period;
+        // Start afresh after a change, or after falling a whole period
+        // behind, rather than rushing to catch up.
+        if !elapsed || Instant::now() > period_start + period {
+            period_start = Instant::now();
+        }
+    }
+    let _ = handle.set_value(0);
+}
+
+/// Waits until `deadline`, unless the settings change from `settings` or
+/// the thread is told to stop first. Returns whether the deadline passed.
+fn sleep_until<'a>(
+    shared: &'a Shared,
+    mut state: MutexGuard<'a, State>,
+    settings: Settings,
+    deadline: Instant,
+) -> (MutexGuard<'a, State>, bool) {
+    loop {
+        if state.stop || state.settings != settings {
+            return (state, false);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return (state, true);
+        }
+        state = shared
+            .changed
+            .wait_timeout(state, deadline - now)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
+    }
+}
+
+fn fail(state: &mut State, error: Error) {
+    log::error!("Stopped generating a signal: {}", error);
+    state.failure = Some(error.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            frequency: 50.0,
+            duty_cycle: 0.5,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn settings_are_accepted_in_the_same_form_as_for_pwm() {
+        let mut s = settings();
+        s.update_from_json(&json::json!({"duty-cycle": "25%", "enabled": "Off"}))
+            .unwrap();
+        assert_eq!((s.duty_cycle, s.enabled), (0.25, false));
+        s.update_from_json(&json::json!({"duty-cycle": "5ms", "enabled": true}))
+            .unwrap();
+        assert_eq!((s.duty_cycle, s.enabled), (0.25, true));
+        s.update_from_json(&json::json!({"frequency": 100, "duty-cycle": "5ms"}))
+            .unwrap();
+        assert_eq!(s.duty_cycle, 0.5);
+        s.update_from_json(&json::json!({"duty-cycle": 0.75}))
+            .unwrap();
+        assert_eq!(s.duty_cycle, 0.75);
+    }
+
+    #[test]
+    fn invalid_settings_are_named() {
+        let update = |value| settings().update_from_json(&value);
+        assert_eq!(
+            update(json::json!({"duty-cycle": "30ms"})),
+            Err("duty-cycle")
+        );
+        assert_eq!(update(json::json!({"duty-cycle": 2})), Err("duty-cycle"));
+        assert_eq!(update(json::json!({"enabled": "maybe"})), Err("enabled"));
+        assert_eq!(update(json::json!({"frequency": 0})), Err("frequency"));
+        assert_eq!(
+            update(json::json!({"frequency": 0, "duty-cycle": "1ms"})),
+            Err("frequency")
+        );
+        assert_eq!(update(json::json!({"frequency": 2000})), Err("frequency"));
+    }
+}
@@ -3,10 +3,10 @@ mod packet;
 mod xmodem_file_adapter;
 
 use crate::{
-    limb::{Error, Limb},
+    limb::{ConfigError, Error, ErrorKind, Limb},
     xmodem::packet::Packet,
     xmodem::xmodem_file_adapter::XModemFileAdapter,
-    port_settings_from_json::port_settings_from_json,
+    port_settings_from_json::{port_settings_from_json, port_settings_to_json},
     system_serial::SerialPort,
 };
 use std::{
@@ -19,13 +19,19 @@ use serde_json as json;
 
 pub struct XModem {
     port: serial::SystemPort,
+    device: String,
+    settings: serial::PortSettings,
     last_status: Option<bool>,
 }
 
 impl XModem {
     fn write(&mut self, packet: &Packet) -> Result<(), Error> {
+        let device = &self.device;
         self.port.write_all(packet.data())
-            .map_err(|_| Error::WriteFailed)
+            .map_err(|e| {
+                let message = format!("writing a packet to {}", device);
+                Error::from_io(ErrorKind::WriteFailed, message, e)
+            })
     }
 
     fn read(&mut self) -> Option<bool> {
@@ -48,28 +54,58 @@ impl XModem {
         while Instant::now() < timeout_point {
             let read = self.read();
             if read.is_some() {
-                return read.ok_or(Error::ReadFailed);
+                return read.ok_or_else(|| ErrorKind::ReadFailed.into());
             }
             sleep(DELAY);
         }
 
-        Err(Error::Timeout)
+        Err(Error::new(ErrorKind::Timeout, format!("waiting for a response on {}", self.device)))
     }
 
     fn wait_for_negative_acknowledge(&mut self) -> Result<(), Error> {
         match self.wait_for_response()? {
             false => Ok(()),
-            true => Err(Error::ReadFailed),
+            true => Err(Error::new(
+                ErrorKind::ReadFailed,
+                "expected the receiver to start with a negative acknowledge",
+            )),
         }
     }
+
+    fn transfer(&mut self, source: File) -> Result<(), Error> {
+        self.wait_for_negative_acknowledge()?;
+        for packet in XModemFileAdapter::new(source) {
+            const MAX_ATTEMPTS : usize = 10;
+            let mut exceeded_max_attempts = true;
+            'repeat_attempts: for _ in 0..MAX_ATTEMPTS {
+                self.write(&packet)?;
+                let acknowledged = self.wait_for_response()?;
+                if acknowledged {
+                    exceeded_max_attempts = false;
+                    break 'repeat_attempts;
+                }
+            }
+            if exceeded_max_attempts {
+                let message = format!("packet not acknowledged after {} attempts", MAX_ATTEMPTS);
+                return Err(Error::new(ErrorKind::BrokenLimb, message));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Limb for XModem {
-    fn from_json(config: &json::Value) -> Option<Self> {
-        let device = config["device"].as_str()?;
-        let mut port = serial::open(device).ok()?;
-
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError> {
+        let device = config["device"].as_str()
+            .ok_or_else(|| ConfigError::new("device", "the path of a serial device"))?;
         let settings = port_settings_from_json(config)?;
+        let mut port = serial::open(device)
+            .map_err(|e| {
+                ConfigError::new("device", "a serial device which can be opened")
+                    .with_serial_source(e)
+            })?;
+
         port.reconfigure(&|s| {
             s.set_baud_rate(settings.baud_rate)?;
             s.set_char_size(settings.char_size);
@@ -77,43 +113,44 @@ impl Limb for XModem {
             s.set_stop_bits(settings.stop_bits);
             s.set_flow_control(settings.flow_control);
             Ok(())
-        }).ok()?;
+        }).map_err(|e| {
+            ConfigError::new("device", "a serial device accepting these settings")
+                .with_serial_source(e)
+        })?;
 
-        Some(Self {
+        Ok(Self {
             port,
+            device: device.to_owned(),
+            settings,
             last_status: None,
         })
     }
 
     fn set(&mut self, value: String) -> Result<(), Error> {
-        let source = File::open(value)
-            .map_err(|_| Error::InvalidValue)?;
-
-        self.wait_for_negative_acknowledge()?;
-        for packet in XModemFileAdapter::new(source) {
-            const MAX_ATTEMPTS : usize = 10;
-            let mut exceeded_max_attempts = true;
-            'repeat_attempts: for _ in 0..MAX_ATTEMPTS {
-                self.write(&packet)?;
-                let acknowledged = self.wait_for_response()?;
-                if acknowledged {
-                    exceeded_max_attempts = false;
-                    break 'repeat_attempts;
-                }
-            }
-            if exceeded_max_attempts { return Err(Error::BrokenLimb); }
-        }
-
-        Ok(())
+        let source = File::open(&value)
+            .map_err(|e| {
+                let message = format!("opening {}", value);
+                Error::new(ErrorKind::InvalidValue, message).with_source(e)
+            })?;
+
+        let result = self.transfer(source);
+        self.last_status = Some(result.is_ok());
+        result
     }
 
     fn get(&mut self) -> Result<String, Error> {
         match self.last_status.take() {
             Some(true) => Ok("Success".to_owned()),
             Some(false) => Ok("Failure".to_owned()),
-            None => Err(Error::InvalidOperation),
+            None => Err(Error::new(ErrorKind::Busy, "no transfer has completed")),
         }
     }
 
     fn type_name(&self) -> &'static str { "xmodem" }
+
+    fn to_json(&self) -> json::Value {
+        let mut config = port_settings_to_json(&self.settings);
+        config["device"] = json::Value::String(self.device.clone());
+        config
+    }
 }
\ No newline at end of file
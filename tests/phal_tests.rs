@@ -10,17 +10,25 @@ extern crate phal;
 extern crate ureq;
 
 use phal::{
-    limb::{Error, Limb, LimbTypes},
+    limb::{no_such_resource, ConfigError, Error, ErrorKind, Limb, LimbTypes, Query},
     server::PHALServer,
 };
 use serde_json as json;
-use std::{collections::HashMap, thread, time};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread, time,
+};
 
 struct MockLimb(String);
 
 impl Limb for MockLimb {
-    fn from_json(_config: &json::Value) -> Option<Self> {
-        Some(MockLimb(String::new()))
+    fn from_json(_config: &json::Value) -> Result<Self, ConfigError> {
+        Ok(MockLimb(String::new()))
     }
 
     fn get(&mut self) -> Result<String, Error> {
@@ -35,6 +43,220 @@ impl Limb for MockLimb {
     fn type_name(&self) -> &'static str {
         "mock-limb"
     }
+
+    fn to_json(&self) -> json::Value {
+        json::json!({})
+    }
+}
+
+/// A limb which takes a while to read, like a long serial transfer.
+struct SlowLimb;
+
+impl Limb for SlowLimb {
+    fn from_json(_config: &json::Value) -> Result<Self, ConfigError> {
+        Ok(SlowLimb)
+    }
+
+    fn get(&mut self) -> Result<String, Error> {
+        thread::sleep(time::Duration::from_millis(500));
+        Ok(String::from("done"))
+    }
+
+    fn set(&mut self, _value: String) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn type_name(&self) -> &'static str {
+        "slow-limb"
+    }
+
+    fn to_json(&self) -> json::Value {
+        json::json!({})
+    }
+}
+
+/// A limb which is read-only and fails to be read in the way its
+/// configuration names.
+struct FailingLimb(String);
+
+impl Limb for FailingLimb {
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError> {
+        let error = config["error"]
+            .as_str()
+            .ok_or_else(|| ConfigError::new("error", "an error name"))?;
+        Ok(FailingLimb(error.to_owned()))
+    }
+
+    fn get(&mut self) -> Result<String, Error> {
+        match self.0.as_ref() {
+            "timeout" => Err(ErrorKind::Timeout.into()),
+            "busy" => Err(ErrorKind::Busy.into()),
+            _ => Err(Error::new(ErrorKind::BrokenLimb, "reading the device")
+                .with_source(io::Error::from_raw_os_error(5))),
+        }
+    }
+
+    fn set(&mut self, _value: String) -> Result<(), Error> {
+        Err(ErrorKind::InvalidOperation.into())
+    }
+
+    fn type_name(&self) -> &'static str {
+        "failing-limb"
+    }
+
+    fn to_json(&self) -> json::Value {
+        json::json!({ "error": self.0 })
+    }
+}
+
+/// A limb with a bug in it, which also panics when it is created if its
+/// configuration says to.
+struct PanickingLimb;
+
+impl Limb for PanickingLimb {
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError> {
+        if config["panic"] == json::Value::Bool(true) {
+            panic!("PanickingLimb::from_json");
+        }
+        Ok(PanickingLimb)
+    }
+
+    fn get(&mut self) -> Result<String, Error> {
+        panic!("PanickingLimb::get")
+    }
+
+    fn set(&mut self, _value: String) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn type_name(&self) -> &'static str {
+        "panicking-limb"
+    }
+
+    fn to_json(&self) -> json::Value {
+        json::json!({})
+    }
+}
+
+/// The number of `CountingLimb`s created so far.
+static LIMBS_CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// A limb which counts how often it is created, with an optional setting
+/// which it always spells out in its configuration.
+struct CountingLimb(String);
+
+impl Limb for CountingLimb {
+    fn from_json(_config: &json::Value) -> Result<Self, ConfigError> {
+        LIMBS_CREATED.fetch_add(1, Ordering::SeqCst);
+        Ok(CountingLimb(String::new()))
+    }
+
+    fn get(&mut self) -> Result<String, Error> {
+        Ok(self.0.clone())
+    }
+
+    fn set(&mut self, value: String) -> Result<(), Error> {
+        self.0 = value;
+        Ok(())
+    }
+
+    fn type_name(&self) -> &'static str {
+        "counting-limb"
+    }
+
+    fn to_json(&self) -> json::Value {
+        json::json!({ "verbose": false })
+    }
+
+    fn config_defaults() -> json::Value {
+        json::json!({ "verbose": false })
+    }
+}
+
+/// Names of devices currently held by an `ExclusiveLimb`.
+static CLAIMED_DEVICES: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// A limb which, like a GPIO line or a serial port, cannot be opened twice.
+struct ExclusiveLimb(String);
+
+impl Limb for ExclusiveLimb {
+    fn from_json(config: &json::Value) -> Result<Self, ConfigError> {
+        let device = config["device"]
+            .as_str()
+            .ok_or_else(|| ConfigError::new("device", "a device name"))?
+            .to_owned();
+        let mut claimed = CLAIMED_DEVICES.lock().unwrap();
+        if claimed
+            .get_or_insert_with(HashSet::new)
+            .insert(device.clone())
+        {
+            Ok(ExclusiveLimb(device))
+        } else {
+            Err(ConfigError::new("device", "a device which is not in use"))
+        }
+    }
+
+    fn get(&mut self) -> Result<String, Error> {
+        Ok(self.0.clone())
+    }
+
+    fn set(&mut self, _value: String) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn type_name(&self) -> &'static str {
+        "exclusive-limb"
+    }
+
+    fn to_json(&self) -> json::Value {
+        json::json!({ "device": self.0 })
+    }
+}
+
+impl Drop for ExclusiveLimb {
+    fn drop(&mut self) {
+        let mut claimed = CLAIMED_DEVICES.lock().unwrap();
+        claimed.get_or_insert_with(HashSet::new).remove(&self.0);
+    }
+}
+
+/// A limb with resources besides its own value, like an input pin's queue
+/// of edge events.
+struct QueueLimb(Vec<String>);
+
+impl Limb for QueueLimb {
+    fn from_json(_config: &json::Value) -> Result<Self, ConfigError> {
+        Ok(QueueLimb(Vec::new()))
+    }
+
+    fn get(&mut self) -> Result<String, Error> {
+        Ok(self.0.len().to_string())
+    }
+
+    fn set(&mut self, value: String) -> Result<(), Error> {
+        self.0.push(value);
+        Ok(())
+    }
+
+    fn get_resource(&mut self, resource: &str, query: &Query) -> Result<json::Value, Error> {
+        match resource {
+            "" => self.get().map(json::Value::String),
+            "items" => Ok(self.0.drain(..).collect()),
+            "items/first" => match query.duration("timeout")? {
+                Some(_) if self.0.is_empty() => Err(ErrorKind::Timeout.into()),
+                _ => Ok(json::json!(self.0.first())),
+            },
+            _ => Err(no_such_resource(resource)),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        "queue-limb"
+    }
+
+    fn to_json(&self) -> json::Value {
+        json::json!({})
+    }
 }
 
 #[test]
@@ -109,3 +331,1066 @@ fn a_limb_is_set_to_its_init_config_property_on_start_up_if_it_exists() {
         "baz".to_string()
     );
 }
+
+#[test]
+fn config_get_request_returns_the_applied_configuration() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", MockLimb)];
+        PHALServer::run_new(types, "localhost:2003").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"foo","init":"baz"},"quux":{"type":"foo"}}"#;
+    ureq::post("http://localhost:2003/config")
+        .send_string(config)
+        .ok();
+    let response = ureq::get("http://localhost:2003/config")
+        .call()
+        .into_string()
+        .unwrap();
+    assert_eq!(
+        json::from_str::<json::Value>(&response).unwrap(),
+        json::from_str::<json::Value>(config).unwrap()
+    );
+}
+
+#[test]
+fn a_failed_configuration_leaves_the_previous_limbs_in_place() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", ExclusiveLimb)];
+        PHALServer::run_new(types, "localhost:2004").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"foo","device":"a"},"baz":{"type":"foo","device":"b"}}"#;
+    assert!(ureq::post("http://localhost:2004/config")
+        .send_string(config)
+        .ok());
+
+    // Moving "a" between limbs only works if its old limb is released first.
+    let moved = r#"{"bar":{"type":"foo","device":"c"},"baz":{"type":"foo","device":"a"}}"#;
+    assert!(ureq::post("http://localhost:2004/config")
+        .send_string(moved)
+        .ok());
+
+    let conflicting = r#"{"bar":{"type":"foo","device":"d"},"baz":{"type":"foo","device":"d"}}"#;
+    assert!(!ureq::post("http://localhost:2004/config")
+        .send_string(conflicting)
+        .ok());
+
+    let response = ureq::get("http://localhost:2004/config")
+        .call()
+        .into_string()
+        .unwrap();
+    assert_eq!(
+        json::from_str::<json::Value>(&response).unwrap(),
+        json::from_str::<json::Value>(moved).unwrap()
+    );
+    assert_eq!(
+        ureq::get("http://localhost:2004/limb/baz")
+            .call()
+            .into_string()
+            .unwrap(),
+        "a".to_string()
+    );
+}
+
+#[test]
+fn a_rejected_configuration_reports_every_bad_limb() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", ExclusiveLimb)];
+        PHALServer::run_new(types, "localhost:2005").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config =
+        r#"{"bar":{"type":"foo"},"baz":{"device":"e"},"quux":{"type":"foo","device":"f"}}"#;
+    let response = ureq::post("http://localhost:2005/config").send_string(config);
+    assert_eq!(response.status(), 400);
+    let errors = json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap();
+    let mut paths: Vec<&str> = errors
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["path"].as_str().unwrap())
+        .collect();
+    paths.sort();
+    assert_eq!(paths, vec!["bar.device", "baz.type"]);
+}
+
+#[test]
+fn unknown_limb_types_are_reported_as_config_errors() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", MockLimb)];
+        PHALServer::run_new(types, "localhost:2006").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"qux"},"baz":"foo"}"#;
+    let response = ureq::post("http://localhost:2006/config").send_string(config);
+    assert_eq!(response.status(), 400);
+    let errors = json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap();
+    let mut paths: Vec<&str> = errors
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["path"].as_str().unwrap())
+        .collect();
+    paths.sort();
+    assert_eq!(paths, vec!["bar.type", "baz"]);
+    assert!(ureq::get("http://localhost:2006/config").call().ok());
+}
+
+#[test]
+fn a_panicking_limb_does_not_stop_the_server() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", PanickingLimb)];
+        PHALServer::run_new(types, "localhost:2007").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"foo"}}"#;
+    assert!(ureq::post("http://localhost:2007/config")
+        .send_string(config)
+        .ok());
+    assert_eq!(
+        ureq::get("http://localhost:2007/limb/bar").call().status(),
+        500
+    );
+    assert_eq!(
+        ureq::get("http://localhost:2007/limb/bar").call().status(),
+        500
+    );
+    assert!(ureq::get("http://localhost:2007/config").call().ok());
+}
+
+#[test]
+fn single_limbs_can_be_added_replaced_and_removed() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", MockLimb)];
+        PHALServer::run_new(types, "localhost:2008").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"foo"},"baz":{"type":"foo"}}"#;
+    assert!(ureq::post("http://localhost:2008/config")
+        .send_string(config)
+        .ok());
+    ureq::post("http://localhost:2008/limb/bar").send_string("untouched");
+
+    assert!(ureq::put("http://localhost:2008/config/quux")
+        .send_string(r#"{"type":"foo","init":"new"}"#)
+        .ok());
+    assert!(ureq::delete("http://localhost:2008/config/baz").call().ok());
+    assert_eq!(
+        ureq::delete("http://localhost:2008/config/baz")
+            .call()
+            .status(),
+        404
+    );
+    assert!(ureq::patch("http://localhost:2008/config")
+        .send_string(r#"{"quux":null,"corge":{"type":"foo"}}"#)
+        .ok());
+
+    let response = ureq::get("http://localhost:2008/config")
+        .call()
+        .into_string()
+        .unwrap();
+    assert_eq!(
+        json::from_str::<json::Value>(&response).unwrap(),
+        json::json!({"bar": {"type": "foo"}, "corge": {"type": "foo"}})
+    );
+    assert_eq!(
+        ureq::get("http://localhost:2008/limb/bar")
+            .call()
+            .into_string()
+            .unwrap(),
+        "untouched".to_string()
+    );
+}
+
+#[test]
+fn a_slow_limb_does_not_stall_requests_for_other_limbs() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", MockLimb), ("slow", SlowLimb)];
+        PHALServer::run_new(types, "localhost:2009").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"foo"},"baz":{"type":"slow"}}"#;
+    assert!(ureq::post("http://localhost:2009/config")
+        .send_string(config)
+        .ok());
+
+    let slow = thread::spawn(|| ureq::get("http://localhost:2009/limb/baz").call().ok());
+    thread::sleep(time::Duration::from_millis(50));
+    let start = time::Instant::now();
+    assert!(ureq::post("http://localhost:2009/limb/bar")
+        .send_string("quux")
+        .ok());
+    assert!(start.elapsed() < time::Duration::from_millis(250));
+    assert!(slow.join().unwrap());
+}
+
+#[test]
+fn json_responses_are_given_when_requested() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", MockLimb)];
+        PHALServer::run_new(types, "localhost:2010").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"foo","init":"baz"}}"#;
+    assert!(ureq::post("http://localhost:2010/config")
+        .send_string(config)
+        .ok());
+
+    let get_json = |url: &str| {
+        let response = ureq::get(url).set("Accept", "application/json").call();
+        assert_eq!(response.content_type(), "application/json");
+        json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap()
+    };
+    assert_eq!(
+        get_json("http://localhost:2010/limb/bar"),
+        json::json!({"limb": "bar", "value": "baz"})
+    );
+    assert_eq!(
+        get_json("http://localhost:2010/info/limbs"),
+        json::json!({"bar": "mock-limb"})
+    );
+    assert_eq!(
+        get_json("http://localhost:2010/limb/quux"),
+        json::json!({
+            "code": 404,
+            "error": "Not found",
+            "message": "That limb does not exist.",
+            "limb": "quux",
+        })
+    );
+}
+
+#[test]
+fn limb_errors_are_given_distinct_status_codes() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", FailingLimb)];
+        PHALServer::run_new(types, "localhost:2011").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{
+        "timeout": {"type": "foo", "error": "timeout"},
+        "busy": {"type": "foo", "error": "busy"},
+        "broken": {"type": "foo", "error": "broken"}
+    }"#;
+    assert!(ureq::post("http://localhost:2011/config")
+        .send_string(config)
+        .ok());
+
+    let status = |limb: &str| {
+        ureq::get(&format!("http://localhost:2011/limb/{}", limb))
+            .call()
+            .status()
+    };
+    assert_eq!(status("timeout"), 504);
+    assert_eq!(status("busy"), 409);
+    assert_eq!(status("broken"), 503);
+
+    let response = ureq::get("http://localhost:2011/limb/broken")
+        .set("Accept", "application/json")
+        .call();
+    let body = json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap();
+    assert_eq!(body["limb"], "broken");
+    let message = body["message"].as_str().unwrap();
+    assert!(message.contains("reading the device"));
+    assert!(message.contains(&io::Error::from_raw_os_error(5).to_string()));
+
+    let response = ureq::post("http://localhost:2011/limb/broken").send_string("High");
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.header("Allow"), Some("GET"));
+}
+
+#[test]
+fn the_configuration_is_restored_from_the_state_file() {
+    let state_file = std::env::temp_dir().join(format!("phal-state-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&state_file);
+
+    let path = state_file.clone();
+    thread::spawn(move || {
+        let types = limb_types![("foo", MockLimb)];
+        let server = PHALServer::new(types, "localhost:2012")
+            .unwrap()
+            .with_state_file(path);
+        server.run()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"foo","init":"baz"}}"#;
+    assert!(ureq::post("http://localhost:2012/config")
+        .send_string(config)
+        .ok());
+    ureq::post("http://localhost:2012/limb/bar").send_string("quux");
+
+    let path = state_file.clone();
+    thread::spawn(move || {
+        let types = limb_types![("foo", MockLimb)];
+        let server = PHALServer::new(types, "localhost:2013")
+            .unwrap()
+            .with_state_file(path);
+        assert_eq!(server.restore_state().ok(), Some(true));
+        server.run()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let response = ureq::get("http://localhost:2013/config")
+        .call()
+        .into_string()
+        .unwrap();
+    assert_eq!(
+        json::from_str::<json::Value>(&response).unwrap(),
+        json::from_str::<json::Value>(config).unwrap()
+    );
+    assert_eq!(
+        ureq::get("http://localhost:2013/limb/bar")
+            .call()
+            .into_string()
+            .unwrap(),
+        "baz".to_string()
+    );
+
+    assert!(ureq::delete("http://localhost:2013/state").call().ok());
+    assert!(!state_file.exists());
+    assert_eq!(
+        ureq::get("http://localhost:2013/state").call().status(),
+        404
+    );
+}
+
+#[test]
+fn limb_resources_are_routed_with_their_query() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", QueueLimb)];
+        PHALServer::run_new(types, "localhost:2014").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    assert!(ureq::post("http://localhost:2014/config")
+        .send_string(r#"{"bar":{"type":"foo"}}"#)
+        .ok());
+    ureq::post("http://localhost:2014/limb/bar").send_string("a");
+    ureq::post("http://localhost:2014/limb/bar").send_string("b");
+    let get = |url: &str| ureq::get(&format!("http://localhost:2014/limb/bar{}", url)).call();
+
+    assert_eq!(get("?ignored=1").into_string().unwrap(), "2");
+    assert_eq!(get("/items/first?timeout=1s").into_string().unwrap(), "a");
+    let response = get("/items");
+    assert_eq!(response.content_type(), "application/json");
+    assert_eq!(response.into_string().unwrap(), r#"["a","b"]"#);
+
+    ureq::post("http://localhost:2014/limb/bar").send_string("c d");
+    let response = ureq::get("http://localhost:2014/limb/bar/items")
+        .set("Accept", "application/json")
+        .call();
+    let body = json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap();
+    assert_eq!(body, json::json!({"limb": "bar", "value": ["c d"]}));
+
+    assert_eq!(get("/items/first?timeout=1s").status(), 504);
+    assert_eq!(get("/items/first?timeout=soon").status(), 400);
+    assert_eq!(get("/nothing").status(), 404);
+}
+
+#[test]
+fn pwm_channels_are_driven_through_sysfs() {
+    let root = std::env::temp_dir().join(format!("phal-pwm-{}", std::process::id()));
+    let channel = root.join("pwmchip0").join("pwm1");
+    std::fs::create_dir_all(&channel).unwrap();
+    for (file, value) in &[
+        ("period", "0"),
+        ("duty_cycle", "0"),
+        ("polarity", "normal"),
+        ("enable", "0"),
+    ] {
+        std::fs::write(channel.join(file), value).unwrap();
+    }
+    let read = |file: &str| std::fs::read_to_string(channel.join(file)).unwrap();
+
+    thread::spawn(|| {
+        let types = limb_types![("pwm", phal::pwm::Pwm)];
+        PHALServer::run_new(types, "localhost:2015").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = json::json!({
+        "fan": {
+            "type": "pwm",
+            "sysfs-root": root,
+            "chip": 0,
+            "channel": 1,
+            "period": "20ms",
+            "duty-cycle": "1500us",
+        }
+    });
+    assert!(ureq::post("http://localhost:2015/config")
+        .send_string(&config.to_string())
+        .ok());
+    assert_eq!(read("period"), "20000000");
+    assert_eq!(read("duty_cycle"), "1500000");
+    assert_eq!(read("enable"), "0");
+
+    assert!(ureq::post("http://localhost:2015/limb/fan")
+        .send_string("On")
+        .ok());
+    assert_eq!(read("enable"), "1");
+    assert!(ureq::post("http://localhost:2015/limb/fan/duty-cycle")
+        .send_string("50%")
+        .ok());
+    assert_eq!(read("duty_cycle"), "10000000");
+    assert!(ureq::post("http://localhost:2015/limb/fan")
+        .send_string(r#"{"polarity": "inversed"}"#)
+        .ok());
+    assert_eq!(read("polarity"), "inversed");
+    assert_eq!(read("enable"), "1");
+
+    let period = ureq::get("http://localhost:2015/limb/fan/period").call();
+    assert_eq!(period.into_string().unwrap(), "20ms");
+    let response = ureq::post("http://localhost:2015/limb/fan/duty-cycle").send_string("30ms");
+    assert_eq!(response.status(), 400);
+    assert_eq!(read("duty_cycle"), "10000000");
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+/// Opens a pseudo-terminal, returning its master side and the path of the
+/// serial device which it emulates.
+fn open_pty() -> (nix::pty::PtyMaster, String) {
+    use nix::{fcntl::OFlag, pty};
+    let master = pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).unwrap();
+    pty::grantpt(&master).unwrap();
+    pty::unlockpt(&master).unwrap();
+    let device = pty::ptsname_r(&master).unwrap();
+    (master, device)
+}
+
+#[test]
+fn serial_data_is_captured_between_requests() {
+    use std::os::unix::io::AsRawFd;
+    let (master, device) = open_pty();
+    let write = |data: &[u8]| nix::unistd::write(master.as_raw_fd(), data).unwrap();
+
+    thread::spawn(|| {
+        let types = limb_types![("serial", phal::serial::Serial)];
+        PHALServer::run_new(types, "localhost:2016").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = json::json!({
+        "uart": {
+            "type": "serial",
+            "device": device,
+            "baud-rate": 115200,
+            "char-size": 8,
+            "parity": "none",
+            "stop-bits": 1,
+            "flow-control": "none",
+            "buffer-size": 8,
+        }
+    });
+    assert!(ureq::post("http://localhost:2016/config")
+        .send_string(&config.to_string())
+        .ok());
+    let get = |url: &str| ureq::get(&format!("http://localhost:2016/limb/uart{}", url)).call();
+
+    write(b"hello");
+    thread::sleep(time::Duration::from_millis(200));
+    let response = get("?peek");
+    assert_eq!(response.header("X-Phal-Dropped"), Some("0"));
+    assert_eq!(response.into_string().unwrap(), "hello");
+    assert_eq!(get("").into_string().unwrap(), "hello");
+    assert_eq!(get("").into_string().unwrap(), "");
+
+    write(b"0123456789");
+    thread::sleep(time::Duration::from_millis(200));
+    let response = ureq::get("http://localhost:2016/limb/uart")
+        .set("Accept", "application/json")
+        .call();
+    let body = json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap();
+    assert_eq!(
+        body,
+        json::json!({"limb": "uart", "value": "23456789", "dropped": 2})
+    );
+
+    assert!(ureq::post("http://localhost:2016/limb/uart")
+        .send_string("ping")
+        .ok());
+    let mut received = [0; 4];
+    nix::unistd::read(master.as_raw_fd(), &mut received).unwrap();
+    assert_eq!(&received, b"ping");
+}
+
+#[test]
+fn serial_output_can_be_waited_for() {
+    use std::os::unix::io::AsRawFd;
+    let (master, device) = open_pty();
+    let fd = master.as_raw_fd();
+    let write = move |data: &[u8]| nix::unistd::write(fd, data).unwrap();
+
+    thread::spawn(|| {
+        let types = limb_types![("serial", phal::serial::Serial)];
+        PHALServer::run_new(types, "localhost:2017").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = json::json!({
+        "uart": {
+            "type": "serial",
+            "device": device,
+            "baud-rate": 115200,
+            "char-size": 8,
+            "parity": "none",
+            "stop-bits": 1,
+            "flow-control": "none",
+        }
+    });
+    assert!(ureq::post("http://localhost:2017/config")
+        .send_string(&config.to_string())
+        .ok());
+    let expect = |query: &str| {
+        let response =
+            ureq::get(&format!("http://localhost:2017/limb/uart/expect?{}", query)).call();
+        assert_eq!(response.status(), 200);
+        json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap()
+    };
+
+    write(b"booting...\r\nBOOT OK\r\nfw v1.23 ok\r\nlogin: ");
+    assert_eq!(
+        expect("text=BOOT+OK"),
+        json::json!({"before": "booting...\r\n", "match": "BOOT OK"})
+    );
+    assert_eq!(
+        expect("regex=v(%5Cd%2B)%5C.%5Cd%2B%7Cnone"),
+        json::json!({"before": "\r\nfw ", "match": "v1.23"})
+    );
+    assert_eq!(
+        expect("regex=%5E[a-z]%2B:%5Cs*"),
+        json::json!({"before": " ok\r\n", "match": "login: "})
+    );
+
+    let get = |url: &str| ureq::get(&format!("http://localhost:2017/limb/uart{}", url)).call();
+    assert_eq!(get("/expect?text=never&timeout=100ms").status(), 504);
+    assert_eq!(get("/expect?regex=a{2").status(), 400);
+    assert_eq!(get("/expect").status(), 400);
+
+    let device = thread::spawn(move || {
+        let mut received = [0; 4];
+        nix::unistd::read(fd, &mut received).unwrap();
+        assert_eq!(&received, b"ping");
+        write(b"junk pong 42\n");
+    });
+    let response =
+        ureq::post("http://localhost:2017/limb/uart/expect?regex=pong%5Cs?%5Cd%2B&timeout=2s")
+            .send_string("ping");
+    device.join().unwrap();
+    let body = json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap();
+    assert_eq!(body, json::json!({"before": "junk ", "match": "pong 42"}));
+    assert_eq!(get("").into_string().unwrap(), "\n");
+    drop(master);
+}
+
+#[test]
+fn serial_data_can_be_binary() {
+    use std::{io::Read, os::unix::io::AsRawFd};
+    let (master, device) = open_pty();
+    let fd = master.as_raw_fd();
+    let write = |data: &[u8]| {
+        nix::unistd::write(fd, data).unwrap();
+        thread::sleep(time::Duration::from_millis(200));
+    };
+    let read = |length: usize| {
+        let mut received = vec![0; length];
+        nix::unistd::read(fd, &mut received).unwrap();
+        received
+    };
+
+    thread::spawn(|| {
+        let types = limb_types![("serial", phal::serial::Serial)];
+        PHALServer::run_new(types, "localhost:2018").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = json::json!({
+        "uart": {
+            "type": "serial",
+            "device": device,
+            "baud-rate": 115200,
+            "char-size": 8,
+            "parity": "none",
+            "stop-bits": 1,
+            "flow-control": "none",
+        }
+    });
+    assert!(ureq::post("http://localhost:2018/config")
+        .send_string(&config.to_string())
+        .ok());
+    let get = |url: &str| ureq::get(&format!("http://localhost:2018/limb/uart{}", url)).call();
+    let post = |url: &str| ureq::post(&format!("http://localhost:2018/limb/uart{}", url));
+
+    write(b"\x00\xff\x10ok");
+    let response = ureq::get("http://localhost:2018/limb/uart")
+        .set("Accept", "application/octet-stream")
+        .call();
+    assert_eq!(response.content_type(), "application/octet-stream");
+    let mut data = Vec::new();
+    response.into_reader().read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"\x00\xff\x10ok");
+
+    write(b"\x00\xff\x10");
+    assert_eq!(get("?encoding=hex&peek").into_string().unwrap(), "00ff10");
+    assert_eq!(get("?encoding=base64").into_string().unwrap(), "AP8Q");
+    assert_eq!(get("?encoding=octal").status(), 400);
+
+    let response = post("")
+        .set("Content-Type", "application/octet-stream")
+        .send_bytes(b"\xde\xad\xbe\xef");
+    assert!(response.ok());
+    assert_eq!(read(4), b"\xde\xad\xbe\xef");
+    assert!(post("?encoding=hex").send_string("c0 ff ee").ok());
+    assert_eq!(read(3), b"\xc0\xff\xee");
+    assert!(post("?encoding=base64").send_string("3q2+7w==").ok());
+    assert_eq!(read(4), b"\xde\xad\xbe\xef");
+
+    assert_eq!(post("?encoding=hex").send_string("c0f").status(), 400);
+    assert_eq!(post("").send_bytes(b"\xff").status(), 400);
+}
+
+#[test]
+fn serial_lines_are_split_and_timestamped() {
+    use std::os::unix::io::AsRawFd;
+    let (master, device) = open_pty();
+    let fd = master.as_raw_fd();
+    let write = |data: &[u8]| {
+        nix::unistd::write(fd, data).unwrap();
+        thread::sleep(time::Duration::from_millis(200));
+    };
+    let now = || {
+        let since = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap();
+        since.as_nanos() as u64
+    };
+
+    thread::spawn(|| {
+        let types = limb_types![("serial", phal::serial::Serial)];
+        PHALServer::run_new(types, "localhost:2019").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let mut config = json::json!({
+        "uart": {
+            "type": "serial",
+            "device": device,
+            "baud-rate": 115200,
+            "char-size": 8,
+            "parity": "none",
+            "stop-bits": 1,
+            "flow-control": "none",
+            "mode": "lines",
+            "line-ending": "\n\r",
+        }
+    });
+    let configure = |config: &json::Value| {
+        ureq::post("http://localhost:2019/config").send_string(&config.to_string())
+    };
+    assert_eq!(configure(&config).status(), 400);
+    config["uart"]["line-ending"] = json::json!("\r\n");
+    assert!(configure(&config).ok());
+    let get = || {
+        let response = ureq::get("http://localhost:2019/limb/uart").call();
+        assert_eq!(response.content_type(), "application/json");
+        json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap()
+    };
+
+    let start = now();
+    write(b"first\r\nsec");
+    let middle = now();
+    write(b"ond\r\nthird\r\npart");
+    let lines = get();
+    let text: Vec<&json::Value> = lines
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| &l["line"])
+        .collect();
+    assert_eq!(text, vec!["first", "second", "third"]);
+    let times: Vec<u64> = lines
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| l["t"].as_u64().unwrap())
+        .collect();
+    assert!(start <= times[0] && times[0] <= middle);
+    assert_eq!(times[0], times[1]);
+    assert!(middle <= times[2] && times[2] <= now());
+
+    assert_eq!(get(), json::json!([]));
+    write(b"ial\r\n");
+    assert_eq!(get()[0]["line"], "partial");
+}
+
+#[test]
+fn serial_modem_lines_and_breaks_have_resources() {
+    let (master, device) = open_pty();
+
+    thread::spawn(|| {
+        let types = limb_types![("serial", phal::serial::Serial)];
+        PHALServer::run_new(types, "localhost:2020").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = json::json!({
+        "uart": {
+            "type": "serial",
+            "device": device,
+            "baud-rate": 115200,
+            "char-size": 8,
+            "parity": "none",
+            "stop-bits": 1,
+            "flow-control": "none",
+        }
+    });
+    assert!(ureq::post("http://localhost:2020/config")
+        .send_string(&config.to_string())
+        .ok());
+    let get = |url: &str| ureq::get(&format!("http://localhost:2020/limb/uart/{}", url)).call();
+    let post = |url: &str, body: &str| {
+        ureq::post(&format!("http://localhost:2020/limb/uart/{}", url)).send_string(body)
+    };
+
+    let start = time::Instant::now();
+    assert!(post("break", "100ms").ok());
+    assert!(start.elapsed() >= time::Duration::from_millis(100));
+    assert_eq!(post("break", "soon").status(), 400);
+    assert_eq!(get("break").status(), 405);
+
+    assert_eq!(post("cts", "High").status(), 405);
+    assert_eq!(post("dtr", "Sideways").status(), 400);
+    assert_eq!(get("nothing").status(), 404);
+    // A pseudo-terminal has no modem lines to read or set.
+    assert_eq!(get("dcd").status(), 503);
+    assert_eq!(get("modem").status(), 503);
+    assert_eq!(post("rts", "Pulse Low 10ms").status(), 503);
+    drop(master);
+}
+
+#[test]
+fn a_limb_which_panics_when_created_leaves_the_previous_limbs_in_place() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", MockLimb), ("boom", PanickingLimb)];
+        PHALServer::run_new(types, "localhost:2021").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"foo","init":"baz"}}"#;
+    assert!(ureq::post("http://localhost:2021/config")
+        .send_string(config)
+        .ok());
+
+    let panicking = r#"{"bar":{"type":"foo"},"quux":{"type":"boom","panic":true}}"#;
+    let response = ureq::post("http://localhost:2021/config").send_string(panicking);
+    assert_eq!(response.status(), 400);
+    let errors = json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap();
+    assert_eq!(errors[0]["limb"], "quux");
+
+    let response = ureq::get("http://localhost:2021/config")
+        .call()
+        .into_string()
+        .unwrap();
+    assert_eq!(
+        json::from_str::<json::Value>(&response).unwrap(),
+        json::from_str::<json::Value>(config).unwrap()
+    );
+    assert_eq!(
+        ureq::get("http://localhost:2021/limb/bar")
+            .call()
+            .into_string()
+            .unwrap(),
+        "baz"
+    );
+}
+
+#[test]
+fn reposting_a_configuration_resets_limbs_without_reopening_them() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", CountingLimb)];
+        PHALServer::run_new(types, "localhost:2022").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"foo","init":"baz"}}"#;
+    assert!(ureq::post("http://localhost:2022/config")
+        .send_string(config)
+        .ok());
+    assert!(ureq::post("http://localhost:2022/limb/bar")
+        .send_string("changed")
+        .ok());
+
+    // Spelling out a default doesn't change the configuration.
+    let spelled_out = r#"{"bar":{"type":"foo","init":"baz","verbose":false}}"#;
+    for config in &[config, spelled_out] {
+        assert!(ureq::post("http://localhost:2022/config")
+            .send_string(config)
+            .ok());
+    }
+    assert_eq!(
+        ureq::get("http://localhost:2022/limb/bar")
+            .call()
+            .into_string()
+            .unwrap(),
+        "baz"
+    );
+    assert_eq!(LIMBS_CREATED.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn a_slow_limb_does_not_hold_up_configuration_changes() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", MockLimb), ("slow", SlowLimb)];
+        PHALServer::run_new(types, "localhost:2023").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"slow"}}"#;
+    assert!(ureq::post("http://localhost:2023/config")
+        .send_string(config)
+        .ok());
+    let slow = thread::spawn(|| ureq::get("http://localhost:2023/limb/bar").call());
+    thread::sleep(time::Duration::from_millis(100));
+
+    let start = time::Instant::now();
+    assert!(ureq::put("http://localhost:2023/config/baz")
+        .send_string(r#"{"type":"foo"}"#)
+        .ok());
+    assert!(ureq::get("http://localhost:2023/limb/baz").call().ok());
+    assert!(start.elapsed() < time::Duration::from_millis(300));
+    assert_eq!(slow.join().unwrap().into_string().unwrap(), "done");
+}
+
+#[test]
+fn serial_settings_the_device_rejects_are_reported() {
+    let (_master, device) = open_pty();
+    thread::spawn(|| {
+        let types = limb_types![("serial", phal::serial::Serial)];
+        PHALServer::run_new(types, "localhost:2025").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = json::json!({
+        "uart": {
+            "type": "serial",
+            "device": device,
+            "baud-rate": 12345,
+            "char-size": 8,
+            "parity": "none",
+            "stop-bits": 1,
+            "flow-control": "none",
+        }
+    });
+    let response = ureq::post("http://localhost:2025/config").send_string(&config.to_string());
+    assert_eq!(response.status(), 400);
+    let errors = json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap();
+    assert_eq!(errors[0]["path"], "uart.baud-rate");
+    assert_eq!(errors[0]["os-error"]["name"], "EINVAL");
+}
+
+#[test]
+fn a_missing_serial_device_is_reported_with_its_errno() {
+    thread::spawn(|| {
+        let types = limb_types![("serial", phal::serial::Serial)];
+        PHALServer::run_new(types, "localhost:2029").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = json::json!({
+        "uart": {
+            "type": "serial",
+            "device": "/dev/ttyNOPE",
+            "baud-rate": 9600,
+            "char-size": 8,
+            "parity": "none",
+            "stop-bits": 1,
+            "flow-control": "none",
+        }
+    });
+    let response = ureq::post("http://localhost:2029/config").send_string(&config.to_string());
+    assert_eq!(response.status(), 400);
+    let errors = json::from_str::<json::Value>(&response.into_string().unwrap()).unwrap();
+    assert_eq!(errors[0]["path"], "uart.device");
+    assert_eq!(errors[0]["os-error"]["errno"], 2);
+    assert_eq!(errors[0]["os-error"]["name"], "ENOENT");
+}
+
+#[test]
+fn limbs_which_cannot_be_restored_are_left_out() {
+    let state_file =
+        std::env::temp_dir().join(format!("phal-partial-state-{}.json", std::process::id()));
+    let saved = r#"{"bar":{"type":"foo","init":"baz"},"quux":{"type":"failing"}}"#;
+    std::fs::write(&state_file, saved).unwrap();
+
+    let path = state_file.clone();
+    thread::spawn(move || {
+        let types = limb_types![("foo", MockLimb), ("failing", FailingLimb)];
+        let server = PHALServer::new(types, "localhost:2026")
+            .unwrap()
+            .with_state_file(path);
+        let errors = server.restore_state().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path(), "quux.error");
+        server.run()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let response = ureq::get("http://localhost:2026/config")
+        .call()
+        .into_string()
+        .unwrap();
+    assert_eq!(
+        json::from_str::<json::Value>(&response).unwrap(),
+        json::json!({"bar": {"type": "foo", "init": "baz"}})
+    );
+    let _ = std::fs::remove_file(&state_file);
+}
+
+#[test]
+fn reading_an_xmodem_limb_before_a_transfer_is_a_conflict() {
+    let (_master, device) = open_pty();
+    thread::spawn(|| {
+        let types = limb_types![("xmodem", phal::xmodem::XModem)];
+        PHALServer::run_new(types, "localhost:2027").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = json::json!({
+        "modem": {
+            "type": "xmodem",
+            "device": device,
+            "baud-rate": 9600,
+            "char-size": 8,
+            "parity": "none",
+            "stop-bits": 1,
+            "flow-control": "none",
+        }
+    });
+    assert!(ureq::post("http://localhost:2027/config")
+        .send_string(&config.to_string())
+        .ok());
+    let response = ureq::get("http://localhost:2027/limb/modem").call();
+    assert_eq!(response.status(), 409);
+    assert_eq!(response.header("Allow"), None);
+}
+
+#[test]
+fn the_outcome_of_an_xmodem_transfer_is_read_once() {
+    use std::os::unix::io::AsRawFd;
+    const ACKNOWLEDGE: u8 = 0x06;
+    const NEGATIVE_ACKNOWLEDGE: u8 = 0x15;
+    const PACKET_SIZE: usize = 132;
+    let (master, device) = open_pty();
+    let source = std::env::temp_dir().join(format!("phal-xmodem-{}.txt", std::process::id()));
+    std::fs::write(&source, "hello").unwrap();
+
+    thread::spawn(|| {
+        let types = limb_types![("xmodem", phal::xmodem::XModem)];
+        PHALServer::run_new(types, "localhost:2030").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = json::json!({
+        "modem": {
+            "type": "xmodem",
+            "device": device,
+            "baud-rate": 9600,
+            "char-size": 8,
+            "parity": "none",
+            "stop-bits": 1,
+            "flow-control": "none",
+        }
+    });
+    assert!(ureq::post("http://localhost:2030/config")
+        .send_string(&config.to_string())
+        .ok());
+
+    let receiver = thread::spawn(move || {
+        let fd = master.as_raw_fd();
+        nix::unistd::write(fd, &[NEGATIVE_ACKNOWLEDGE]).unwrap();
+        let mut packet = Vec::new();
+        while packet.len() < PACKET_SIZE {
+            let mut buffer = [0; PACKET_SIZE];
+            let read = nix::unistd::read(fd, &mut buffer).unwrap();
+            packet.extend_from_slice(&buffer[..read]);
+        }
+        nix::unistd::write(fd, &[ACKNOWLEDGE]).unwrap();
+        let mut end = [0; 1];
+        nix::unistd::read(fd, &mut end).unwrap();
+        nix::unistd::write(fd, &[ACKNOWLEDGE]).unwrap();
+        (master, packet, end[0])
+    });
+    assert!(ureq::post("http://localhost:2030/limb/modem")
+        .send_string(source.to_str().unwrap())
+        .ok());
+    let (_master, packet, end) = receiver.join().unwrap();
+    assert_eq!(&packet[..8], &[0x01, 1, 254, b'h', b'e', b'l', b'l', b'o']);
+    assert_eq!(end, 0x04);
+
+    assert_eq!(
+        ureq::get("http://localhost:2030/limb/modem")
+            .call()
+            .into_string()
+            .unwrap(),
+        "Success"
+    );
+    assert_eq!(
+        ureq::get("http://localhost:2030/limb/modem")
+            .call()
+            .status(),
+        409
+    );
+    let _ = std::fs::remove_file(&source);
+}
+
+#[test]
+fn waiting_for_a_slow_limb_to_be_reset_or_removed_does_not_hold_up_other_limbs() {
+    thread::spawn(|| {
+        let types = limb_types![("foo", MockLimb), ("slow", SlowLimb)];
+        PHALServer::run_new(types, "localhost:2028").unwrap()
+    });
+    thread::sleep(time::Duration::from_millis(10));
+
+    let config = r#"{"bar":{"type":"slow","init":"on"},"baz":{"type":"foo","init":"quux"}}"#;
+    assert!(ureq::post("http://localhost:2028/config")
+        .send_string(config)
+        .ok());
+    let other_limb_responds_quickly = || {
+        let start = time::Instant::now();
+        assert!(ureq::get("http://localhost:2028/limb/baz").call().ok());
+        assert!(start.elapsed() < time::Duration::from_millis(200));
+    };
+
+    let slow = thread::spawn(|| ureq::get("http://localhost:2028/limb/bar").call());
+    thread::sleep(time::Duration::from_millis(100));
+    let reset =
+        thread::spawn(move || ureq::post("http://localhost:2028/config").send_string(config));
+    thread::sleep(time::Duration::from_millis(50));
+    other_limb_responds_quickly();
+    assert!(reset.join().unwrap().ok());
+    assert!(slow.join().unwrap().ok());
+
+    let slow = thread::spawn(|| ureq::get("http://localhost:2028/limb/bar").call());
+    thread::sleep(time::Duration::from_millis(100));
+    let removal = thread::spawn(|| ureq::delete("http://localhost:2028/config/bar").call());
+    thread::sleep(time::Duration::from_millis(50));
+    other_limb_responds_quickly();
+    assert!(removal.join().unwrap().ok());
+    assert!(slow.join().unwrap().ok());
+    assert_eq!(
+        ureq::get("http://localhost:2028/limb/bar").call().status(),
+        404
+    );
+}